@@ -4,6 +4,10 @@
 #include "bochs.h"
 #include "cpu/cpu.h"
 #include "cpu/apic.h"
+#include "cpu/cpuid.h"
+#if BX_SUPPORT_AMX
+#include "cpu/avx/amx.h"
+#endif
 #include "pc_system.h"
 
 typedef BX_CPU_C *BX_CPU_C_PTR;
@@ -65,6 +69,14 @@ BOCHSAPI void cpu_set_mode(unsigned id) {
 #endif
 }
 
+BOCHSAPI void cpu_flush_tlb(unsigned id) {
+    BX_CPU(id)->TLB_flush();
+}
+
+BOCHSAPI void cpu_flush_icache(unsigned id) {
+    BX_CPU(id)->iCache.flushICacheEntries();
+}
+
 // general purpose regs
 
 BOCHSAPI bx_address cpu_get_pc(unsigned id) {
@@ -468,6 +480,50 @@ BOCHSAPI void cpu_set_interrupt_ssp_table(unsigned id, Bit64u v) {
     BX_CPU(id)->msr.ia32_interrupt_ssp_table = v;
 }
 
+BOCHSAPI Bit64u cpu_get_xss(unsigned id) {
+#if BX_CPU_LEVEL >= 6
+    return BX_CPU(id)->msr.ia32_xss;
+#else
+    return 0;
+#endif
+}
+
+BOCHSAPI void cpu_set_xss(unsigned id, Bit64u v) {
+#if BX_CPU_LEVEL >= 6
+    BX_CPU(id)->msr.ia32_xss = v;
+#endif
+}
+
+// protection keys, set_PKeys also recomputes the per key access masks
+
+BOCHSAPI Bit32u cpu_get_pkru(unsigned id) {
+#if BX_SUPPORT_PKEYS
+    return BX_CPU(id)->pkru;
+#else
+    return 0;
+#endif
+}
+
+BOCHSAPI void cpu_set_pkru(unsigned id, Bit32u v) {
+#if BX_SUPPORT_PKEYS
+    BX_CPU(id)->set_PKeys(v, BX_CPU(id)->pkrs);
+#endif
+}
+
+BOCHSAPI Bit32u cpu_get_pkrs(unsigned id) {
+#if BX_SUPPORT_PKEYS
+    return BX_CPU(id)->pkrs;
+#else
+    return 0;
+#endif
+}
+
+BOCHSAPI void cpu_set_pkrs(unsigned id, Bit32u v) {
+#if BX_SUPPORT_PKEYS
+    BX_CPU(id)->set_PKeys(BX_CPU(id)->pkru, v);
+#endif
+}
+
 // ZMM
 
 BOCHSAPI void cpu_get_zmm(unsigned id, unsigned reg, Bit64u z[]) {
@@ -522,6 +578,93 @@ BOCHSAPI void cpu_set_zmm(unsigned id, unsigned reg, Bit64u z[]) {
 #endif
 }
 
+// opmask
+
+BOCHSAPI Bit64u cpu_get_opmask(unsigned id, unsigned reg) {
+#if BX_SUPPORT_EVEX
+    return BX_CPU(id)->opmask[reg].rrx;
+#else
+    return 0;
+#endif
+}
+
+BOCHSAPI void cpu_set_opmask(unsigned id, unsigned reg, Bit64u v) {
+#if BX_SUPPORT_EVEX
+    BX_CPU(id)->opmask[reg].rrx = v;
+#endif
+}
+
+// AMX, the tile state is only allocated when the cpu model supports AMX, and
+// otherwise reads as zero
+
+BOCHSAPI void cpu_get_tilecfg(unsigned id, Bit8u *palette_id, Bit8u *start_row,
+        Bit16u colsb[], Bit8u rows[])
+{
+    *palette_id = 0;
+    *start_row = 0;
+    memset(colsb, 0, 8 * sizeof(Bit16u));
+    memset(rows, 0, 8 * sizeof(Bit8u));
+
+#if BX_SUPPORT_AMX
+    AMX *amx = BX_CPU(id)->amx;
+
+    if (amx) {
+        *palette_id = amx->palette_id;
+        *start_row = amx->start_row;
+
+        for (unsigned i = 0; i < 8; i++) {
+            colsb[i] = amx->tilecfg[i].bytes_per_row;
+            rows[i] = amx->tilecfg[i].rows;
+        }
+    }
+#endif
+}
+
+BOCHSAPI void cpu_set_tilecfg(unsigned id, Bit8u palette_id, Bit8u start_row,
+        const Bit16u colsb[], const Bit8u rows[])
+{
+#if BX_SUPPORT_AMX
+    AMX *amx = BX_CPU(id)->amx;
+
+    if (amx) {
+        amx->palette_id = palette_id;
+        amx->start_row = start_row;
+
+        for (unsigned i = 0; i < 8; i++) {
+            amx->tilecfg[i].bytes_per_row = colsb[i];
+            amx->tilecfg[i].rows = rows[i];
+        }
+    }
+#endif
+}
+
+// 16 rows of 8 qwords
+BOCHSAPI void cpu_get_tile(unsigned id, unsigned reg, Bit64u t[]) {
+    memset(t, 0, 16 * 8 * sizeof(Bit64u));
+
+#if BX_SUPPORT_AMX
+    AMX *amx = BX_CPU(id)->amx;
+
+    if (amx) {
+        for (unsigned row = 0; row < 16; row++)
+            for (unsigned q = 0; q < 8; q++)
+                t[row * 8 + q] = amx->tile[reg].row[row].zmm_u64[q];
+    }
+#endif
+}
+
+BOCHSAPI void cpu_set_tile(unsigned id, unsigned reg, const Bit64u t[]) {
+#if BX_SUPPORT_AMX
+    AMX *amx = BX_CPU(id)->amx;
+
+    if (amx) {
+        for (unsigned row = 0; row < 16; row++)
+            for (unsigned q = 0; q < 8; q++)
+                amx->tile[reg].row[row].zmm_u64[q] = t[row * 8 + q];
+    }
+#endif
+}
+
 // FP registers
 
 BOCHSAPI Bit16u cpu_get_fp_cw(unsigned id) {
@@ -556,6 +699,38 @@ BOCHSAPI void cpu_set_fp_op(unsigned id, Bit16u v) {
     BX_CPU(id)->the_i387.foo = v;
 }
 
+BOCHSAPI Bit64u cpu_get_fp_ip(unsigned id) {
+    return BX_CPU(id)->the_i387.fip;
+}
+
+BOCHSAPI void cpu_set_fp_ip(unsigned id, Bit64u v) {
+    BX_CPU(id)->the_i387.fip = v;
+}
+
+BOCHSAPI Bit64u cpu_get_fp_dp(unsigned id) {
+    return BX_CPU(id)->the_i387.fdp;
+}
+
+BOCHSAPI void cpu_set_fp_dp(unsigned id, Bit64u v) {
+    BX_CPU(id)->the_i387.fdp = v;
+}
+
+BOCHSAPI Bit16u cpu_get_fp_cs(unsigned id) {
+    return BX_CPU(id)->the_i387.fcs;
+}
+
+BOCHSAPI void cpu_set_fp_cs(unsigned id, Bit16u v) {
+    BX_CPU(id)->the_i387.fcs = v;
+}
+
+BOCHSAPI Bit16u cpu_get_fp_ds(unsigned id) {
+    return BX_CPU(id)->the_i387.fds;
+}
+
+BOCHSAPI void cpu_set_fp_ds(unsigned id, Bit16u v) {
+    BX_CPU(id)->the_i387.fds = v;
+}
+
 BOCHSAPI void cpu_get_fp_st(unsigned id, unsigned reg, Bit64u *fraction, Bit16u *exp) {
     const floatx80 f = BX_CPU(id)->the_i387.st_space[reg];
     *fraction = f.signif;
@@ -569,6 +744,27 @@ BOCHSAPI void cpu_set_fp_st(unsigned id, unsigned reg, const Bit64u fraction, co
     BX_CPU(id)->the_i387.st_space[reg] = f;
 }
 
+BOCHSAPI unsigned cpu_get_cpl(unsigned id) {
+    return BX_CPU(id)->sregs[BX_SEG_REG_CS].selector.rpl;
+}
+
+BOCHSAPI Bit64u cpu_get_icount(unsigned id) {
+    return BX_CPU(id)->get_icount();
+}
+
+BOCHSAPI unsigned cpu_get_activity_state(unsigned id) {
+    return BX_CPU(id)->activity_state;
+}
+
+BOCHSAPI void cpu_set_activity_state(unsigned id, unsigned state) {
+    BX_CPU_C *c = BX_CPU(id);
+
+    c->activity_state = state;
+
+    // have the cpu loop notice the change before the next instruction
+    c->async_event = 1;
+}
+
 BOCHSAPI Bit32u cpu_get_cpu_mode(unsigned id) {
     return BX_CPU(id)->get_cpu_mode();
 }
@@ -606,6 +802,42 @@ BOCHSAPI void cpu_clear_killbit(unsigned id) {
 BOCHSAPI void cpu_exception(unsigned id, unsigned vector, Bit16u error) {
     BX_CPU(id)->exception(vector, error);
 }
+
+// these go through the same logic as the RDMSR and WRMSR instructions, a
+// false return is where the instruction would #GP
+
+// whether CPUID would exit to a VMX or SVM hypervisor
+BOCHSAPI bool cpu_in_guest(unsigned id) {
+#if BX_SUPPORT_VMX
+    if (BX_CPU(id)->in_vmx_guest)
+        return true;
+#endif
+#if BX_SUPPORT_SVM
+    if (BX_CPU(id)->in_svm_guest)
+        return true;
+#endif
+    return false;
+}
+
+BOCHSAPI bool cpu_rdmsr(unsigned id, Bit32u index, Bit64u *v) {
+    return BX_CPU(id)->rdmsr(index, v);
+}
+
+BOCHSAPI bool cpu_wrmsr(unsigned id, Bit32u index, Bit64u v) {
+    return BX_CPU(id)->wrmsr(index, v);
+}
+
+// what the CPUID instruction would return, without any of its side effects
+BOCHSAPI void cpu_cpuid(unsigned id, Bit32u leaf, Bit32u subleaf, Bit32u *r) {
+    cpuid_function_t f;
+
+    BX_CPU(id)->cpuid->get_cpuid_leaf(leaf, subleaf, &f);
+
+    r[0] = f.eax;
+    r[1] = f.ebx;
+    r[2] = f.ecx;
+    r[3] = f.edx;
+}
 }
 
 Bit8u bx_cpu_count = 0xff; // max number of processsors
@@ -10,6 +10,21 @@ unsigned instr_bx_opcode(void *i) {
     return instr->getIaOpcode();
 }
 
+unsigned instr_ilen(void *i) {
+    bxInstruction_c *instr = (bxInstruction_c *)i;
+    return instr->ilen();
+}
+
+bool instr_is_rdmsr(void *i) {
+    bxInstruction_c *instr = (bxInstruction_c *)i;
+    return instr->getIaOpcode() == BX_IA_RDMSR;
+}
+
+bool instr_is_cpuid(void *i) {
+    bxInstruction_c *instr = (bxInstruction_c *)i;
+    return instr->getIaOpcode() == BX_IA_CPUID;
+}
+
 Bit16u instr_imm16(void *i) {
     bxInstruction_c *instr = (bxInstruction_c *)i;
     return instr->Iw();
@@ -5,21 +5,46 @@
 // with var args versions
 //
 // 4/18/19 so because were stuck with this shit right now we spend a lot of
-// time inside vsnprintf, only to discard the formatted results. We'll hack
-// around this by not doing shit on release builds.
+// time inside vsnprintf, only to discard the formatted results. Each level
+// is checked against a mask before formatting, which is empty on release
+// builds until the rust side enables something.
 #include <stdio.h>
 #include <stdarg.h>
+#include <stdlib.h>
+#include <string.h>
 
 #include "bochs.h"
 
 namespace rust {
 extern "C" {
-	void logfunctions_error(const char *);
-	void logfunctions_ldebug(const char *);
-	void logfunctions_lwarn(const char *);
-	void logfunctions_info(const char *);
-	void logfunctions_panic(const char *);
-	void logfunctions_fatal1(const char *);
+	void logfunctions_error(const char *, const char *);
+	void logfunctions_ldebug(const char *, const char *);
+	void logfunctions_lwarn(const char *, const char *);
+	void logfunctions_info(const char *, const char *);
+	void logfunctions_panic(const char *, const char *);
+	void logfunctions_fatal1(const char *, const char *);
+}
+}
+
+// these must match LogLevel in logfunctions.rs
+#define RUST_LOG_DEBUG (1 << 0)
+#define RUST_LOG_INFO  (1 << 1)
+#define RUST_LOG_WARN  (1 << 2)
+#define RUST_LOG_ERROR (1 << 3)
+
+#ifndef RUST_CC_RELEASE
+static unsigned log_mask = RUST_LOG_DEBUG | RUST_LOG_INFO | RUST_LOG_WARN | RUST_LOG_ERROR;
+#else
+static unsigned log_mask = 0;
+#endif
+
+extern "C" {
+BOCHSAPI unsigned logfunctions_get_mask(void) {
+	return log_mask;
+}
+
+BOCHSAPI void logfunctions_set_mask(unsigned mask) {
+	log_mask = mask;
 }
 }
 
@@ -31,11 +56,17 @@ int logfunctions::default_onoff[N_LOGLEV] =
   ACT_FATAL    // on panic, quit
 };
 
-logfunctions::logfunctions(void) {}
-logfunctions::~logfunctions(void) {}
+logfunctions::logfunctions(void) : name(NULL), prefix(NULL) {}
+
+logfunctions::~logfunctions(void) {
+	free(name);
+	free(prefix);
+}
 
 void logfunctions::error(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
+	if (!(log_mask & RUST_LOG_ERROR))
+		return;
+
 	char buf[0x1000];
 
 	va_list args;
@@ -43,12 +74,11 @@ void logfunctions::error(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_error(buf);
-#endif
+	rust::logfunctions_error(prefix, buf);
 }
 
+// panics are always reported, as they stop the cpu even on release builds
 void logfunctions::fatal1(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
 	char buf[0x1000];
 
 	va_list args;
@@ -56,12 +86,13 @@ void logfunctions::fatal1(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_fatal1(buf);
-#endif
+	rust::logfunctions_fatal1(prefix, buf);
 }
 
 void logfunctions::info(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
+	if (!(log_mask & RUST_LOG_INFO))
+		return;
+
 	char buf[0x1000];
 
 	va_list args;
@@ -69,12 +100,13 @@ void logfunctions::info(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_info(buf);
-#endif
+	rust::logfunctions_info(prefix, buf);
 }
 
 void logfunctions::ldebug(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
+	if (!(log_mask & RUST_LOG_DEBUG))
+		return;
+
 	char buf[0x1000];
 
 	va_list args;
@@ -82,12 +114,13 @@ void logfunctions::ldebug(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_ldebug(buf);
-#endif
+	rust::logfunctions_ldebug(prefix, buf);
 }
 
 void logfunctions::lwarn(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
+	if (!(log_mask & RUST_LOG_WARN))
+		return;
+
 	char buf[0x1000];
 
 	va_list args;
@@ -95,12 +128,11 @@ void logfunctions::lwarn(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_lwarn(buf);
-#endif
+	rust::logfunctions_lwarn(prefix, buf);
 }
 
+// panics are always reported, as they stop the cpu even on release builds
 void logfunctions::panic(const char *fmt, ...) {
-#ifndef RUST_CC_RELEASE
 	char buf[0x1000];
 
 	va_list args;
@@ -108,10 +140,15 @@ void logfunctions::panic(const char *fmt, ...) {
 	vsnprintf(buf, sizeof buf, fmt, args);
 	va_end(args);
 
-	rust::logfunctions_panic(buf);
-#endif
+	rust::logfunctions_panic(prefix, buf);
 }
 
-void logfunctions::put(const char *p, const char *q) {}
+void logfunctions::put(const char *n, const char *p) {
+	free(name);
+	free(prefix);
+
+	name = strdup(n);
+	prefix = strdup(p);
+}
 
 BOCHSAPI class logfunctions *genlog = NULL;
@@ -108,6 +108,43 @@ BOCHSAPI bx_param_string_c* sim_new_param_string(const char *name, const char *v
 BOCHSAPI void sim_delete_param_string(bx_param_string_c *b) {
     delete b;
 }
+
+BOCHSAPI Bit32u sim_param_enum_get(bx_param_enum_c *e) {
+    return e->get();
+}
+
+BOCHSAPI void sim_param_enum_set(bx_param_enum_c *e, Bit32u idx) {
+    e->set(idx);
+}
+
+BOCHSAPI Bit64u sim_param_num_get(bx_param_num_c *n) {
+    return n->get64();
+}
+
+BOCHSAPI void sim_param_num_set(bx_param_num_c *n, Bit64u val) {
+    n->set(val);
+}
+
+BOCHSAPI bool sim_param_bool_get(bx_param_bool_c *b) {
+    return b->get();
+}
+
+BOCHSAPI void sim_param_bool_set(bx_param_bool_c *b, bool val) {
+    b->set(val);
+}
+
+BOCHSAPI const char *sim_param_string_get(bx_param_string_c *s) {
+    return s->getptr();
+}
+
+BOCHSAPI void sim_param_string_set(bx_param_string_c *s, const char *val) {
+    s->set(val);
+}
+
+// 1 if the value was accepted, the same as bochs' bochsrc parser checks
+BOCHSAPI int sim_param_parse(bx_param_c *p, const char *val) {
+    return p->parse_param(val);
+}
 }
 
 logfunctions *siminterface_log = NULL;
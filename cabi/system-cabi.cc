@@ -1,6 +1,12 @@
 #include "bochs.h"
 #include "pc_system.h"
 
+namespace rust {
+extern "C" {
+    void countdown_event(void);
+}
+}
+
 bx_pc_system_c::bx_pc_system_c() {
     a20_mask =  BX_CONST64(0xffffffffffffffff);
     kill_bochs_request = 0;
@@ -33,9 +39,12 @@ int bx_pc_system_c::Reset(unsigned int) { assert(false); return 0; }
 
 bool bx_pc_system_c::get_enable_a20(void) { assert(false); return true; }
 
+// tickn calls this whenever the countdown runs out, including from the HLT
+// loop, so keeping the countdown at 1 gives rust a look at every tick
 void bx_pc_system_c::countdownEvent(void)
 {
     bx_pc_system.currCountdown = 1;
+    rust::countdown_event();
 }
 void bx_pc_system_c::invlpg(bx_address addr) { assert(false); }
 
@@ -0,0 +1,380 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+
+use crate::cpu::live_cpus;
+use crate::params::{ParamBool, ParamEnum, ParamNum, ParamString};
+use crate::sim::{param_bool, param_enum, param_num, param_string};
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum BochsrcError {
+    /// bochs only reads its configuration when a cpu is created, so it
+    /// cannot be changed while any cpu exists
+    CpuExists,
+    /// The line (1 based) is not `directive: key=value, ...`
+    Syntax(usize),
+    /// The directive is not `cpu` or `cpuid`, e.g. `memory` or `romimage`,
+    /// which configure devices bochscpu does not have
+    UnsupportedDirective(usize, String),
+    /// There is no such parameter, e.g. `cpuid.foo`
+    UnknownKey(usize, String),
+    /// The value does not parse as the parameter's type or is not one of
+    /// its choices
+    InvalidValue(usize, String),
+    /// The number is outside the parameter's range, or the string is longer
+    /// than it can hold
+    OutOfRange(usize, String),
+    /// bochs accepts the value but bochscpu cannot run with it, e.g.
+    /// `cpu: reset_on_triple_fault=1`
+    UnsupportedValue(usize, String),
+}
+
+impl fmt::Display for BochsrcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for BochsrcError {
+    fn description(&self) -> &str {
+        "bochsrc parse error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+// keys bochs accepts which have no meaning here
+const IGNORED: &[&str] = &["cpu.ips"];
+
+enum Assign {
+    Enum(&'static ParamEnum, usize),
+    Num(&'static ParamNum, u64),
+    Bool(&'static ParamBool, bool),
+    String(&'static ParamString, CString),
+}
+
+impl Assign {
+    fn apply(&self) {
+        match self {
+            Self::Enum(p, v) => p.set(*v),
+            Self::Num(p, v) => p.set(*v),
+            Self::Bool(p, v) => p.set(*v),
+            Self::String(p, v) => p.set(v),
+        }
+    }
+}
+
+// split on `sep` outside of double quotes
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+// bochs' own parser stops at the first character it does not understand and
+// takes `abc` as 0, so check the syntax it documents before handing it over:
+// decimal with an optional K or M suffix, or 0x prefixed hex
+fn num_literal(val: &str) -> bool {
+    if let Some(hex) = val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        return !hex.is_empty() && hex.bytes().all(|b| b.is_ascii_hexdigit());
+    }
+
+    let digits = val.strip_suffix(['K', 'M']).unwrap_or(val);
+
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn num(line: usize, key: &str, val: u64) -> Result<Assign, BochsrcError> {
+    let p = param_num(key).ok_or_else(|| BochsrcError::UnknownKey(line, key.to_string()))?;
+    let (min, max) = p.range();
+
+    if val < min || val > max {
+        return Err(BochsrcError::OutOfRange(line, key.to_string()));
+    }
+
+    Ok(Assign::Num(p, val))
+}
+
+fn assign(line: usize, key: &str, val: &str) -> Result<Assign, BochsrcError> {
+    let invalid = || BochsrcError::InvalidValue(line, key.to_string());
+    let cval = CString::new(val).map_err(|_| invalid())?;
+
+    if let Some(p) = param_enum(key) {
+        let idx = p.parse(&cval).ok_or_else(invalid)?;
+
+        return Ok(Assign::Enum(p, idx));
+    }
+
+    if let Some(p) = param_num(key) {
+        if !num_literal(val) {
+            return Err(invalid());
+        }
+
+        let v = p.parse(&cval).ok_or_else(invalid)?;
+
+        return num(line, key, v);
+    }
+
+    if let Some(p) = param_bool(key) {
+        let v = p.parse(&cval).ok_or_else(invalid)?;
+
+        // the reset path reads the cmos, which bochscpu does not have
+        if key == "cpu.reset_on_triple_fault" && v {
+            return Err(BochsrcError::UnsupportedValue(line, key.to_string()));
+        }
+
+        return Ok(Assign::Bool(p, v));
+    }
+
+    if let Some(p) = param_string(key) {
+        if val.len() > p.max_len() {
+            return Err(BochsrcError::OutOfRange(line, key.to_string()));
+        }
+
+        return Ok(Assign::String(p, cval));
+    }
+
+    Err(BochsrcError::UnknownKey(line, key.to_string()))
+}
+
+// count=processors[:cores[:threads]]
+fn count(line: usize, val: &str) -> Result<Vec<Assign>, BochsrcError> {
+    let keys = ["cpu.n_processors", "cpu.n_cores", "cpu.n_threads"];
+    let parts: Vec<&str> = val.split(':').collect();
+
+    if parts.len() > keys.len() {
+        return Err(BochsrcError::InvalidValue(line, "cpu.count".to_string()));
+    }
+
+    parts
+        .iter()
+        .zip(keys)
+        .map(|(p, key)| {
+            let invalid = || BochsrcError::InvalidValue(line, "cpu.count".to_string());
+
+            if !num_literal(p) {
+                return Err(invalid());
+            }
+
+            let p = CString::new(*p).map_err(|_| invalid())?;
+            let v = param_num(key)
+                .ok_or_else(|| BochsrcError::UnknownKey(line, key.to_string()))?
+                .parse(&p)
+                .ok_or_else(invalid)?;
+
+            num(line, key, v)
+        })
+        .collect()
+}
+
+// split a line into (directive.key, unquoted value) pairs, comments and blank
+// lines yield nothing
+fn parse_line(n: usize, line: &str) -> Result<Vec<(String, &str)>, BochsrcError> {
+    let line = split_unquoted(line, '#')[0].trim();
+
+    if line.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (directive, params) = line.split_once(':').ok_or(BochsrcError::Syntax(n))?;
+    let directive = directive.trim();
+
+    if directive != "cpu" && directive != "cpuid" {
+        return Err(BochsrcError::UnsupportedDirective(n, directive.to_string()));
+    }
+
+    split_unquoted(params, ',')
+        .into_iter()
+        .map(str::trim)
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (k, v) = param.split_once('=').ok_or(BochsrcError::Syntax(n))?;
+
+            Ok((format!("{}.{}", directive, k.trim()), unquote(v.trim())))
+        })
+        .collect()
+}
+
+/// Configure bochs from the `cpu:` and `cpuid:` lines of a bochsrc
+///
+/// These are the only directives honored. Anything else in a full bochs
+/// configuration, such as `memory:` or `romimage:`, is rejected with
+/// `BochsrcError::UnsupportedDirective` rather than silently dropped, so
+/// those lines need to be removed first. `cpu: ips=` is accepted and
+/// ignored, and `cpu: reset_on_triple_fault=1` is rejected with
+/// `BochsrcError::UnsupportedValue` as there is no cmos for the reset path.
+/// Nothing is changed unless the whole file is valid.
+///
+/// # Safety
+///
+/// The same as `Config::apply`.
+pub unsafe fn load_bochsrc(text: &str) -> Result<(), BochsrcError> {
+    if live_cpus() != 0 {
+        return Err(BochsrcError::CpuExists);
+    }
+
+    let mut assigns = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+
+        for (key, val) in parse_line(n, line)? {
+            if key == "cpu.count" {
+                assigns.extend(count(n, val)?);
+            } else if IGNORED.contains(&key.as_str()) {
+                warn!("ignoring bochsrc parameter {}", key);
+            } else {
+                assigns.push(assign(n, &key, val)?);
+            }
+        }
+    }
+
+    assigns.iter().for_each(Assign::apply);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(line: &str) -> Vec<(String, &str)> {
+        parse_line(1, line).unwrap()
+    }
+
+    #[test]
+    fn blank_and_comments() {
+        assert!(pairs("").is_empty());
+        assert!(pairs("   ").is_empty());
+        assert!(pairs("# cpu: model=corei7_skylake_x").is_empty());
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            pairs("cpu: model=corei7_skylake_x, count=1:2:1  # trailing"),
+            [
+                ("cpu.model".to_string(), "corei7_skylake_x"),
+                ("cpu.count".to_string(), "1:2:1"),
+            ]
+        );
+
+        assert_eq!(
+            pairs("cpuid : simd = avx2 ,, x86_64=1,"),
+            [
+                ("cpuid.simd".to_string(), "avx2"),
+                ("cpuid.x86_64".to_string(), "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(
+            pairs(r#"cpu: brand_string="a, b # c", add_features="avx512f""#),
+            [
+                ("cpu.brand_string".to_string(), "a, b # c"),
+                ("cpu.add_features".to_string(), "avx512f"),
+            ]
+        );
+
+        assert_eq!(split_unquoted(r#"a,"b,c",d"#, ','), ["a", r#""b,c""#, "d"]);
+        assert_eq!(unquote(r#""x""#), "x");
+        assert_eq!(unquote(r#""x"#), r#""x"#);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_line(3, "cpu model=x"), Err(BochsrcError::Syntax(3)));
+        assert_eq!(parse_line(4, "cpu: model"), Err(BochsrcError::Syntax(4)));
+        assert_eq!(
+            parse_line(5, "memory: guest=512"),
+            Err(BochsrcError::UnsupportedDirective(5, "memory".to_string()))
+        );
+    }
+
+    #[test]
+    fn num_literals() {
+        for v in ["0", "12", "4K", "2M", "0x10", "0XfF"] {
+            assert!(num_literal(v), "{}", v);
+        }
+
+        for v in ["", "abc", "12abc", "-1", "K", "4G", "4k", "0x", "0xg", " 1"] {
+            assert!(!num_literal(v), "{}", v);
+        }
+    }
+
+    #[test]
+    fn num_suffixes() {
+        let p = param_num("cpu.n_processors").unwrap();
+
+        assert_eq!(p.parse(c"12"), Some(12));
+        assert_eq!(p.parse(c"0x10"), Some(0x10));
+        assert_eq!(p.parse(c"4K"), Some(4_000));
+        assert_eq!(p.parse(c"2M"), Some(2_000_000));
+        assert_eq!(p.parse(c""), None);
+    }
+
+    #[test]
+    fn rejected_values() {
+        let err = |key, val| assign(2, key, val).err();
+
+        assert_eq!(
+            err("cpu.quantum", "abc"),
+            Some(BochsrcError::InvalidValue(2, "cpu.quantum".to_string()))
+        );
+        assert_eq!(
+            count(2, "1:abc").err(),
+            Some(BochsrcError::InvalidValue(2, "cpu.count".to_string()))
+        );
+        assert_eq!(
+            err("cpu.reset_on_triple_fault", "1"),
+            Some(BochsrcError::UnsupportedValue(
+                2,
+                "cpu.reset_on_triple_fault".to_string()
+            ))
+        );
+        assert_eq!(err("cpu.reset_on_triple_fault", "0"), None);
+    }
+
+    #[test]
+    fn cpu_line() {
+        let line = "cpu: count=1, ips=50000000, model=pentium_mmx, \
+                    reset_on_triple_fault=0, cpuid_limit_winnt=0, mwait_is_nop=0";
+
+        for (key, val) in pairs(line) {
+            if key == "cpu.count" {
+                assert!(count(1, val).is_ok());
+            } else if !IGNORED.contains(&key.as_str()) {
+                assert!(assign(1, &key, val).is_ok(), "{}", key);
+            }
+        }
+
+        for model in ["corei7_skylake_x", "core_duo_t2400_yonah", "tigerlake"] {
+            assert!(assign(1, "cpu.model", model).is_ok(), "{}", model);
+        }
+
+        assert!(assign(1, "cpuid.model", "0x9e").is_ok());
+        assert!(assign(1, "cpuid.stepping", "10").is_ok());
+    }
+}
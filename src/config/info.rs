@@ -0,0 +1,114 @@
+use crate::sim::{
+    param_queried, params_bool, params_enum, params_missing, params_num, params_string,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ParamKind {
+    Enum,
+    Num,
+    Bool,
+    String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ParamValue {
+    Enum {
+        value: &'static str,
+        choices: Vec<&'static str>,
+    },
+    Num {
+        value: u64,
+        min: u64,
+        max: u64,
+    },
+    Bool(bool),
+    String {
+        value: String,
+        max_len: usize,
+    },
+}
+
+impl ParamValue {
+    pub fn kind(&self) -> ParamKind {
+        match self {
+            Self::Enum { .. } => ParamKind::Enum,
+            Self::Num { .. } => ParamKind::Num,
+            Self::Bool(_) => ParamKind::Bool,
+            Self::String { .. } => ParamKind::String,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ParamInfo {
+    /// The bochs parameter path, e.g. `cpu.model`
+    pub key: &'static str,
+    pub value: ParamValue,
+    /// Whether bochs has looked the parameter up, i.e. whether it had any
+    /// effect on the cpus created so far
+    pub queried: bool,
+}
+
+/// Every parameter bochscpu provides to bochs, with its current value
+pub fn params() -> Vec<ParamInfo> {
+    let enums = params_enum().iter().map(|(k, p)| {
+        let choices: Vec<_> = p.choices().collect();
+
+        (
+            *k,
+            ParamValue::Enum {
+                value: choices[p.get()],
+                choices,
+            },
+        )
+    });
+
+    let nums = params_num().iter().map(|(k, p)| {
+        let (min, max) = p.range();
+
+        (
+            *k,
+            ParamValue::Num {
+                value: p.get(),
+                min,
+                max,
+            },
+        )
+    });
+
+    let bools = params_bool()
+        .iter()
+        .map(|(k, p)| (*k, ParamValue::Bool(p.get())));
+
+    let strings = params_string().iter().map(|(k, p)| {
+        (
+            *k,
+            ParamValue::String {
+                value: p.get(),
+                max_len: p.max_len(),
+            },
+        )
+    });
+
+    let mut v: Vec<_> = enums
+        .chain(nums)
+        .chain(bools)
+        .chain(strings)
+        .map(|(key, value)| ParamInfo {
+            key,
+            value,
+            queried: param_queried(key),
+        })
+        .collect();
+
+    v.sort_by_key(|p| p.key);
+    v
+}
+
+/// Parameters bochs looked up which bochscpu does not provide
+///
+/// bochs falls back to its own behaviour for these, which is usually to
+/// treat the feature as disabled.
+pub fn missing_params() -> Vec<(ParamKind, String)> {
+    params_missing().iter().cloned().collect()
+}
@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+
+use crate::cpu::live_cpus;
+use crate::sim::{BRAND_STRING_LEN, FEATURES_LEN, param_bool, param_enum, param_string};
+
+mod bochsrc;
+pub use bochsrc::{BochsrcError, load_bochsrc};
+
+mod info;
+pub use info::{ParamInfo, ParamKind, ParamValue, missing_params, params};
+
+/// The bochs cpu models, in the same order as `cpu.model` in sim.rs
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CpuModel {
+    /// The only model which is built from the `cpuid.*` parameters
+    BxGeneric = 0,
+    I486dx4,
+    Pentium,
+    PentiumMmx,
+    AmdK62Chomper,
+    AthlonXp,
+    P2Klamath,
+    P3Katmai,
+    P4Willamette,
+    CoreDuoT2500Yonah,
+    AtomN270,
+    P4PrescottCeleron336,
+    Athlon64Clawhammer,
+    Athlon64Venice,
+    Turion64Tyler,
+    Phenom8650Toliman,
+    Core2PenrynT9600,
+    Corei5Lynnfield750,
+    Corei5ArrandaleM520,
+    Corei7SandyBridge2600k,
+    Zambezi,
+    TrinityApu,
+    Ryzen,
+    Corei7IvyBridge3770k,
+    Corei7Haswell4770,
+    BroadwellUlt,
+    Corei7SkylakeX,
+    Corei3Cnl,
+    Corei7IcelakeU,
+    Tigerlake,
+    SapphireRapids,
+    ArrowLake,
+}
+
+/// The highest SIMD extension of the generic model, in the same order as
+/// `cpuid.simd` in sim.rs
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Simd {
+    None = 0,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse4_1,
+    Sse4_2,
+    Avx,
+    Avx2,
+}
+
+/// The boolean `cpuid.*` parameters of the generic model
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CpuidFeature {
+    Mmx,
+    Sse4a,
+    MisalignedSse,
+    Sep,
+    Xsave,
+    Xsaveopt,
+    Aes,
+    Sha,
+    Adx,
+    X86_64,
+    Fsgsbase,
+    Pcid,
+    Smep,
+    Smap,
+    Cet,
+    Mwait,
+    Movbe,
+    Pages1G,
+    AvxF16c,
+    AvxFma,
+    Fma4,
+    Xop,
+    Tbm,
+}
+
+impl CpuidFeature {
+    pub fn param(self) -> &'static str {
+        match self {
+            Self::Mmx => "cpuid.mmx",
+            Self::Sse4a => "cpuid.sse4a",
+            Self::MisalignedSse => "cpuid.misaligned_sse",
+            Self::Sep => "cpuid.sep",
+            Self::Xsave => "cpuid.xsave",
+            Self::Xsaveopt => "cpuid.xsaveopt",
+            Self::Aes => "cpuid.aes",
+            Self::Sha => "cpuid.sha",
+            Self::Adx => "cpuid.adx",
+            Self::X86_64 => "cpuid.x86_64",
+            Self::Fsgsbase => "cpuid.fsgsbase",
+            Self::Pcid => "cpuid.pcid",
+            Self::Smep => "cpuid.smep",
+            Self::Smap => "cpuid.smap",
+            Self::Cet => "cpuid.cet",
+            Self::Mwait => "cpuid.mwait",
+            Self::Movbe => "cpuid.movbe",
+            Self::Pages1G => "cpuid.1g_pages",
+            Self::AvxF16c => "cpuid.avx_f16c",
+            Self::AvxFma => "cpuid.avx_fma",
+            Self::Fma4 => "cpuid.fma4",
+            Self::Xop => "cpuid.xop",
+            Self::Tbm => "cpuid.tbm",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ConfigError {
+    /// bochs only reads its configuration when a cpu is created, so it
+    /// cannot be changed while any cpu exists
+    CpuExists,
+    /// The parameter is ignored by every model other than `BxGeneric`
+    RequiresGenericModel(&'static str),
+    /// The first parameter is enabled without the second one it depends on
+    Requires(&'static str, &'static str),
+    /// A feature is both added and excluded
+    FeatureConflict(String),
+    /// A feature name is empty or contains a separator
+    InvalidFeature(String),
+    /// The value is longer than the parameter can hold
+    TooLong(&'static str),
+    /// The value contains a nul byte
+    InteriorNul(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "cpu configuration error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+/// Changes to the bochs cpu configuration, applied before the first cpu is
+/// created
+///
+/// Anything which is not set keeps its current value, which until a
+/// `Config` has been applied is the `Tigerlake` model with AVX2.
+///
+/// ```ignore
+/// Config::new()
+///     .model(CpuModel::BxGeneric)
+///     .simd(Simd::Avx2)
+///     .cpuid(CpuidFeature::Movbe, true)
+///     .cpuid(CpuidFeature::Pages1G, true)
+///     .apply()?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    model: Option<CpuModel>,
+    simd: Option<Simd>,
+    cpuid: BTreeMap<CpuidFeature, bool>,
+    add_features: Vec<String>,
+    exclude_features: Vec<String>,
+    brand_string: Option<String>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn model(mut self, model: CpuModel) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Only used by `CpuModel::BxGeneric`
+    pub fn simd(mut self, simd: Simd) -> Self {
+        self.simd = Some(simd);
+        self
+    }
+
+    /// Only used by `CpuModel::BxGeneric`
+    pub fn cpuid(mut self, feature: CpuidFeature, enabled: bool) -> Self {
+        self.cpuid.insert(feature, enabled);
+        self
+    }
+
+    /// Add a feature, by its bochs name (e.g. `"avx512f"`), to any model
+    pub fn add_feature(mut self, name: &str) -> Self {
+        self.add_features.push(name.to_string());
+        self
+    }
+
+    /// Remove a feature, by its bochs name, from any model
+    pub fn exclude_feature(mut self, name: &str) -> Self {
+        self.exclude_features.push(name.to_string());
+        self
+    }
+
+    /// At most 48 bytes, as returned by CPUID leaves 0x80000002 to 0x80000004
+    pub fn brand_string(mut self, brand: &str) -> Self {
+        self.brand_string = Some(brand.to_string());
+        self
+    }
+
+    fn effective_model(&self) -> usize {
+        match self.model {
+            Some(m) => m as usize,
+            None => param_enum("cpu.model").unwrap().get(),
+        }
+    }
+
+    fn effective_simd(&self) -> usize {
+        match self.simd {
+            Some(s) => s as usize,
+            None => param_enum("cpuid.simd").unwrap().get(),
+        }
+    }
+
+    fn effective_cpuid(&self, feature: CpuidFeature) -> bool {
+        match self.cpuid.get(&feature) {
+            Some(v) => *v,
+            None => param_bool(feature.param()).unwrap().get(),
+        }
+    }
+
+    /// Check the configuration for combinations bochs would refuse or ignore
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.effective_model() != CpuModel::BxGeneric as usize {
+            if self.simd.is_some() {
+                return Err(ConfigError::RequiresGenericModel("cpuid.simd"));
+            }
+
+            if let Some(f) = self.cpuid.keys().next() {
+                return Err(ConfigError::RequiresGenericModel(f.param()));
+            }
+        } else {
+            self.validate_generic()?;
+        }
+
+        for f in self.add_features.iter().chain(&self.exclude_features) {
+            if f.is_empty() || f.contains([',', ' ', '\t']) {
+                return Err(ConfigError::InvalidFeature(f.clone()));
+            }
+        }
+
+        if let Some(f) = self
+            .add_features
+            .iter()
+            .find(|f| self.exclude_features.contains(f))
+        {
+            return Err(ConfigError::FeatureConflict(f.clone()));
+        }
+
+        let too_long =
+            |l: &[String]| l.iter().map(|f| f.len() + 1).sum::<usize>() > FEATURES_LEN + 1;
+
+        if too_long(&self.add_features) {
+            return Err(ConfigError::TooLong("cpu.add_features"));
+        }
+
+        if too_long(&self.exclude_features) {
+            return Err(ConfigError::TooLong("cpu.exclude_features"));
+        }
+
+        if let Some(b) = &self.brand_string {
+            if b.len() > BRAND_STRING_LEN {
+                return Err(ConfigError::TooLong("cpu.brand_string"));
+            }
+
+            if b.contains('\0') {
+                return Err(ConfigError::InteriorNul("cpu.brand_string"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_generic(&self) -> Result<(), ConfigError> {
+        let avx = self.effective_simd() >= Simd::Avx as usize;
+
+        if avx && !self.effective_cpuid(CpuidFeature::Xsave) {
+            return Err(ConfigError::Requires(
+                "cpuid.simd",
+                CpuidFeature::Xsave.param(),
+            ));
+        }
+
+        let requires = [
+            (CpuidFeature::Xsaveopt, CpuidFeature::Xsave),
+            (CpuidFeature::Pages1G, CpuidFeature::X86_64),
+            (CpuidFeature::Pcid, CpuidFeature::X86_64),
+            (CpuidFeature::Fsgsbase, CpuidFeature::X86_64),
+        ];
+
+        for (f, dep) in requires {
+            if self.effective_cpuid(f) && !self.effective_cpuid(dep) {
+                return Err(ConfigError::Requires(f.param(), dep.param()));
+            }
+        }
+
+        let requires_avx = [
+            CpuidFeature::AvxF16c,
+            CpuidFeature::AvxFma,
+            CpuidFeature::Fma4,
+            CpuidFeature::Xop,
+        ];
+
+        for f in requires_avx {
+            if self.effective_cpuid(f) && !avx {
+                return Err(ConfigError::Requires(f.param(), "cpuid.simd"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the configuration and hand it to bochs
+    ///
+    /// This must happen before the first cpu is created, or after every cpu
+    /// has been deleted.
+    ///
+    /// # Safety
+    ///
+    /// bochs parameters are global and unsynchronized, so this must not race
+    /// with `Cpu::new` on another thread.
+    pub unsafe fn apply(&self) -> Result<(), ConfigError> {
+        if live_cpus() != 0 {
+            return Err(ConfigError::CpuExists);
+        }
+
+        self.validate()?;
+
+        if let Some(m) = self.model {
+            param_enum("cpu.model").unwrap().set(m as usize);
+        }
+
+        if let Some(s) = self.simd {
+            param_enum("cpuid.simd").unwrap().set(s as usize);
+        }
+
+        for (f, v) in &self.cpuid {
+            param_bool(f.param()).unwrap().set(*v);
+        }
+
+        if !self.add_features.is_empty() {
+            let s = CString::new(self.add_features.join(",")).unwrap();
+            param_string("cpu.add_features").unwrap().set(&s);
+        }
+
+        if !self.exclude_features.is_empty() {
+            let s = CString::new(self.exclude_features.join(",")).unwrap();
+            param_string("cpu.exclude_features").unwrap().set(&s);
+        }
+
+        if let Some(b) = &self.brand_string {
+            let s = CString::new(b.as_str()).unwrap();
+            param_string("cpu.brand_string").unwrap().set(&s);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every parameter validate looks at is set, so bochs is never consulted
+    fn generic() -> Config {
+        [
+            CpuidFeature::Xsave,
+            CpuidFeature::Xsaveopt,
+            CpuidFeature::X86_64,
+            CpuidFeature::Pages1G,
+            CpuidFeature::Pcid,
+            CpuidFeature::Fsgsbase,
+            CpuidFeature::AvxF16c,
+            CpuidFeature::AvxFma,
+            CpuidFeature::Fma4,
+            CpuidFeature::Xop,
+        ]
+        .into_iter()
+        .fold(
+            Config::new().model(CpuModel::BxGeneric).simd(Simd::Sse2),
+            |c, f| c.cpuid(f, false),
+        )
+    }
+
+    #[test]
+    fn generic_only() {
+        let c = Config::new().model(CpuModel::Corei7SkylakeX);
+
+        assert_eq!(c.validate(), Ok(()));
+        assert_eq!(
+            c.clone().simd(Simd::Avx2).validate(),
+            Err(ConfigError::RequiresGenericModel("cpuid.simd"))
+        );
+        assert_eq!(
+            c.cpuid(CpuidFeature::Movbe, true).validate(),
+            Err(ConfigError::RequiresGenericModel("cpuid.movbe"))
+        );
+    }
+
+    #[test]
+    fn dependencies() {
+        assert_eq!(generic().validate(), Ok(()));
+
+        assert_eq!(
+            generic().simd(Simd::Avx).validate(),
+            Err(ConfigError::Requires("cpuid.simd", "cpuid.xsave"))
+        );
+        assert_eq!(
+            generic()
+                .simd(Simd::Avx2)
+                .cpuid(CpuidFeature::Xsave, true)
+                .validate(),
+            Ok(())
+        );
+
+        assert_eq!(
+            generic().cpuid(CpuidFeature::Xsaveopt, true).validate(),
+            Err(ConfigError::Requires("cpuid.xsaveopt", "cpuid.xsave"))
+        );
+
+        for f in [
+            CpuidFeature::Pages1G,
+            CpuidFeature::Pcid,
+            CpuidFeature::Fsgsbase,
+        ] {
+            assert_eq!(
+                generic().cpuid(f, true).validate(),
+                Err(ConfigError::Requires(f.param(), "cpuid.x86_64"))
+            );
+            assert_eq!(
+                generic()
+                    .cpuid(f, true)
+                    .cpuid(CpuidFeature::X86_64, true)
+                    .validate(),
+                Ok(())
+            );
+        }
+
+        for f in [
+            CpuidFeature::AvxF16c,
+            CpuidFeature::AvxFma,
+            CpuidFeature::Fma4,
+            CpuidFeature::Xop,
+        ] {
+            assert_eq!(
+                generic().simd(Simd::Sse4_2).cpuid(f, true).validate(),
+                Err(ConfigError::Requires(f.param(), "cpuid.simd"))
+            );
+        }
+    }
+
+    #[test]
+    fn features() {
+        assert_eq!(
+            generic().add_feature("").validate(),
+            Err(ConfigError::InvalidFeature(String::new()))
+        );
+        assert_eq!(
+            generic().exclude_feature("avx512f,sha").validate(),
+            Err(ConfigError::InvalidFeature("avx512f,sha".to_string()))
+        );
+        assert_eq!(
+            generic()
+                .add_feature("avx512f")
+                .exclude_feature("avx512f")
+                .validate(),
+            Err(ConfigError::FeatureConflict("avx512f".to_string()))
+        );
+        assert_eq!(
+            (0..FEATURES_LEN)
+                .fold(generic(), |c, _| c.add_feature("x"))
+                .validate(),
+            Err(ConfigError::TooLong("cpu.add_features"))
+        );
+    }
+
+    #[test]
+    fn brand_string() {
+        assert_eq!(
+            generic()
+                .brand_string(&"x".repeat(BRAND_STRING_LEN))
+                .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            generic()
+                .brand_string(&"x".repeat(BRAND_STRING_LEN + 1))
+                .validate(),
+            Err(ConfigError::TooLong("cpu.brand_string"))
+        );
+        assert_eq!(
+            generic().brand_string("a\0b").validate(),
+            Err(ConfigError::InteriorNul("cpu.brand_string"))
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use crate::Address;
+use crate::cpu::{GlobalSeg, Seg, State};
+
+// segment attributes, in the same layout as `Seg::attr`: descriptor bits
+// 40..56, i.e. type, S, DPL, P, limit 19:16, AVL, L, D/B, G
+const ATTR_CODE64_KERNEL: u16 = 0xaf9b;
+const ATTR_CODE64_USER: u16 = 0xaffb;
+const ATTR_CODE32_KERNEL: u16 = 0xcf9b;
+const ATTR_CODE32_USER: u16 = 0xcffb;
+const ATTR_DATA_KERNEL: u16 = 0xcf93;
+const ATTR_DATA_USER: u16 = 0xcff3;
+const ATTR_TSS_BUSY: u16 = 0x008b;
+const ATTR_REAL_DATA: u16 = 0x0093;
+const ATTR_REAL_LDT: u16 = 0x0082;
+
+const CR0_PE: u32 = 1 << 0;
+const CR0_MP: u32 = 1 << 1;
+const CR0_ET: u32 = 1 << 4;
+const CR0_NE: u32 = 1 << 5;
+const CR0_WP: u32 = 1 << 16;
+const CR0_AM: u32 = 1 << 18;
+const CR0_NW: u32 = 1 << 29;
+const CR0_CD: u32 = 1 << 30;
+const CR0_PG: u32 = 1 << 31;
+
+const CR4_PAE: u32 = 1 << 5;
+const CR4_PGE: u32 = 1 << 7;
+const CR4_OSFXSR: u32 = 1 << 9;
+const CR4_OSXMMEXCPT: u32 = 1 << 10;
+const CR4_OSXSAVE: u32 = 1 << 18;
+
+const EFER_SCE: u32 = 1 << 0;
+const EFER_LME: u32 = 1 << 8;
+const EFER_LMA: u32 = 1 << 10;
+const EFER_NXE: u32 = 1 << 11;
+
+const TSS_LIMIT: u32 = 0x67;
+
+const fn seg(selector: u16, attr: u16) -> Seg {
+    Seg {
+        present: true,
+        selector,
+        base: 0,
+        limit: 0xffff_ffff,
+        attr,
+    }
+}
+
+const fn real_seg(attr: u16) -> Seg {
+    Seg {
+        present: true,
+        selector: 0,
+        base: 0,
+        limit: 0xffff,
+        attr,
+    }
+}
+
+// encode a segment as the 8 byte descriptor the guest would load it from
+fn descriptor(s: &Seg) -> u64 {
+    let attr = s.attr as u64;
+    let base = s.base;
+
+    let limit = if attr & (1 << 15) != 0 {
+        (s.limit >> 12) as u64
+    } else {
+        s.limit as u64
+    };
+
+    (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | (attr & 0xff) << 40
+        | (limit >> 16 & 0xf) << 48
+        | (attr >> 12 & 0xf) << 52
+        | (base >> 24 & 0xff) << 56
+}
+
+/// Builder for a coherent initial `State`
+///
+/// The protected and long mode presets use a flat GDT with the layout below,
+/// which `gdt_bytes` produces for writing into guest memory at the base set
+/// with `gdt`. The segment caches in the state already match it, so the GDT
+/// only needs to exist if the guest reloads a segment or takes an interrupt.
+///
+/// ```text
+/// 0x08 64-bit kernel code   0x20 user data
+/// 0x10 kernel data          0x28 64-bit user code
+/// 0x18 32-bit user code     0x30 32-bit kernel code
+///                           0x38 TSS (16 bytes)
+/// ```
+///
+/// STAR is set up for this layout, so SYSCALL and SYSRET work once LSTAR
+/// has been set.
+pub struct StateBuilder {
+    state: State,
+}
+
+impl StateBuilder {
+    pub const KERNEL_CS: u16 = 0x08;
+    pub const KERNEL_DS: u16 = 0x10;
+    pub const USER_CS32: u16 = 0x18 | 3;
+    pub const USER_DS: u16 = 0x20 | 3;
+    pub const USER_CS: u16 = 0x28 | 3;
+    pub const KERNEL_CS32: u16 = 0x30;
+    pub const TSS: u16 = 0x38;
+
+    const GDT_LIMIT: u16 = 0x47;
+
+    // everything which is common to the protected and long mode presets
+    fn flat(cs: Seg, ds: Seg) -> State {
+        State {
+            rflags: 0x202,
+
+            es: ds,
+            cs,
+            ss: ds,
+            ds,
+            fs: ds,
+            gs: ds,
+
+            tr: Seg {
+                present: true,
+                selector: Self::TSS,
+                base: 0,
+                limit: TSS_LIMIT,
+                attr: ATTR_TSS_BUSY,
+            },
+            gdtr: GlobalSeg {
+                base: 0,
+                limit: Self::GDT_LIMIT,
+            },
+
+            dr6: 0xffff_0ff0,
+            dr7: 0x400,
+
+            xcr0: 1,
+
+            fpcw: 0x37f,
+            fptw: 0xffff,
+            mxcsr: 0x1f80,
+            mxcsr_mask: 0xffbf,
+
+            apic_base: 0xfee0_0900,
+            pat: 0x0007_0406_0007_0406,
+
+            ..Default::default()
+        }
+    }
+
+    fn long64(cs: Seg, ds: Seg) -> Self {
+        let mut state = Self::flat(cs, ds);
+
+        state.cr0 = CR0_PE | CR0_MP | CR0_ET | CR0_NE | CR0_WP | CR0_AM | CR0_PG;
+        state.cr4 = CR4_PAE | CR4_PGE | CR4_OSFXSR | CR4_OSXMMEXCPT | CR4_OSXSAVE;
+        state.efer = EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE;
+        state.xcr0 = 0x7;
+
+        state.star = (Self::KERNEL_CS as u64) << 32 | 0x18 << 48;
+        state.sfmask = 0x4700;
+
+        Self { state }
+    }
+
+    /// 64-bit ring 3 with paging, SSE and AVX enabled
+    pub fn long64_user() -> Self {
+        Self::long64(
+            seg(Self::USER_CS, ATTR_CODE64_USER),
+            seg(Self::USER_DS, ATTR_DATA_USER),
+        )
+    }
+
+    /// 64-bit ring 0 with paging, SSE and AVX enabled
+    pub fn long64_kernel() -> Self {
+        Self::long64(
+            seg(Self::KERNEL_CS, ATTR_CODE64_KERNEL),
+            seg(Self::KERNEL_DS, ATTR_DATA_KERNEL),
+        )
+    }
+
+    /// 32-bit ring 0 with flat 4G segments and paging disabled
+    pub fn protected32_flat() -> Self {
+        let mut state = Self::flat(
+            seg(Self::KERNEL_CS32, ATTR_CODE32_KERNEL),
+            seg(Self::KERNEL_DS, ATTR_DATA_KERNEL),
+        );
+
+        state.cr0 = CR0_PE | CR0_ET | CR0_NE;
+        state.cr4 = CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+        Self { state }
+    }
+
+    /// Real mode with every segment at 0, as after reset apart from CS
+    pub fn real_mode() -> Self {
+        let data = real_seg(ATTR_REAL_DATA);
+
+        let state = State {
+            rflags: 0x2,
+
+            es: data,
+            cs: data,
+            ss: data,
+            ds: data,
+            fs: data,
+            gs: data,
+
+            ldtr: real_seg(ATTR_REAL_LDT),
+            tr: real_seg(ATTR_TSS_BUSY),
+            gdtr: GlobalSeg {
+                base: 0,
+                limit: 0xffff,
+            },
+            idtr: GlobalSeg {
+                base: 0,
+                limit: 0x3ff,
+            },
+
+            cr0: CR0_CD | CR0_NW | CR0_ET,
+
+            dr6: 0xffff_0ff0,
+            dr7: 0x400,
+
+            xcr0: 1,
+
+            fpcw: 0x37f,
+            fptw: 0xffff,
+            mxcsr: 0x1f80,
+            mxcsr_mask: 0xffbf,
+
+            apic_base: 0xfee0_0900,
+            pat: 0x0007_0406_0007_0406,
+
+            ..Default::default()
+        };
+
+        Self { state }
+    }
+
+    pub fn rip(mut self, rip: Address) -> Self {
+        self.state.rip = rip;
+        self
+    }
+
+    pub fn stack(mut self, rsp: Address) -> Self {
+        self.state.rsp = rsp;
+        self
+    }
+
+    pub fn cr3(mut self, cr3: u64) -> Self {
+        self.state.cr3 = cr3;
+        self
+    }
+
+    /// Where the guest will find the GDT from `gdt_bytes`
+    pub fn gdt(mut self, base: Address) -> Self {
+        self.state.gdtr.base = base;
+        self
+    }
+
+    pub fn idt(mut self, base: Address, limit: u16) -> Self {
+        self.state.idtr = GlobalSeg { base, limit };
+        self
+    }
+
+    pub fn tss(mut self, base: Address) -> Self {
+        self.state.tr.base = base;
+        self
+    }
+
+    pub fn fs_base(mut self, base: Address) -> Self {
+        self.state.fs.base = base;
+        self
+    }
+
+    pub fn gs_base(mut self, base: Address) -> Self {
+        self.state.gs.base = base;
+        self
+    }
+
+    pub fn kernel_gs_base(mut self, base: Address) -> Self {
+        self.state.kernel_gs_base = base;
+        self
+    }
+
+    pub fn lstar(mut self, lstar: Address) -> Self {
+        self.state.lstar = lstar;
+        self
+    }
+
+    /// The GDT matching the protected and long mode presets, including the
+    /// TSS descriptor for the base set with `tss`
+    pub fn gdt_bytes(&self) -> Vec<u8> {
+        let tr = &self.state.tr;
+
+        let entries = [
+            0,
+            descriptor(&seg(Self::KERNEL_CS, ATTR_CODE64_KERNEL)),
+            descriptor(&seg(Self::KERNEL_DS, ATTR_DATA_KERNEL)),
+            descriptor(&seg(Self::USER_CS32, ATTR_CODE32_USER)),
+            descriptor(&seg(Self::USER_DS, ATTR_DATA_USER)),
+            descriptor(&seg(Self::USER_CS, ATTR_CODE64_USER)),
+            descriptor(&seg(Self::KERNEL_CS32, ATTR_CODE32_KERNEL)),
+            descriptor(tr),
+            // the upper half of a 64-bit system descriptor
+            tr.base >> 32,
+        ];
+
+        entries.iter().flat_map(|e| e.to_le_bytes()).collect()
+    }
+
+    pub fn build(self) -> State {
+        self.state
+    }
+}
@@ -1,12 +1,21 @@
 use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::ptr;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::hook::{self, HookEvent, Hooks, set_hook_event};
-use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref_mut};
+use crate::cpuid::{CpuidResult, CpuidTable};
+use crate::hook::{self, HookEvent, Hooks, MsrOverride, msr_override, set_hook_event};
+use crate::mem::{self, GuestMemory, PagingMode, VirtMemError};
+use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref, ptr_to_ref_mut};
 use crate::{Address, NUM_CPUS, PhyAddress};
 
+mod builder;
+pub use builder::StateBuilder;
+
 mod state;
 pub use state::State;
 
@@ -20,6 +29,8 @@ unsafe extern "C" {
     fn cpu_loop(id: u32);
 
     fn cpu_set_mode(id: u32);
+    fn cpu_flush_tlb(id: u32);
+    fn cpu_flush_icache(id: u32);
 
     fn cpu_get_pc(id: u32) -> u64;
     fn cpu_set_pc(id: u32, val: u64);
@@ -137,6 +148,14 @@ unsafe extern "C" {
     fn cpu_set_fp_tw(id: u32, val: u16);
     fn cpu_get_fp_op(id: u32) -> u16;
     fn cpu_set_fp_op(id: u32, val: u16);
+    fn cpu_get_fp_ip(id: u32) -> u64;
+    fn cpu_set_fp_ip(id: u32, val: u64);
+    fn cpu_get_fp_dp(id: u32) -> u64;
+    fn cpu_set_fp_dp(id: u32, val: u64);
+    fn cpu_get_fp_cs(id: u32) -> u16;
+    fn cpu_set_fp_cs(id: u32, val: u16);
+    fn cpu_get_fp_ds(id: u32) -> u16;
+    fn cpu_set_fp_ds(id: u32, val: u16);
     fn cpu_get_fp_st(id: u32, reg: u32, val: *mut u64, exp: *mut u16);
     fn cpu_set_fp_st(id: u32, reg: u32, val: u64, exp: u16);
 
@@ -155,7 +174,32 @@ unsafe extern "C" {
     fn cpu_get_interrupt_ssp_table(id: u32) -> u64;
     fn cpu_set_interrupt_ssp_table(id: u32, val: u64);
 
+    fn cpu_get_xss(id: u32) -> u64;
+    fn cpu_set_xss(id: u32, val: u64);
+    fn cpu_get_pkru(id: u32) -> u32;
+    fn cpu_set_pkru(id: u32, val: u32);
+    fn cpu_get_pkrs(id: u32) -> u32;
+    fn cpu_set_pkrs(id: u32, val: u32);
+
+    fn cpu_get_opmask(id: u32, reg: u32) -> u64;
+    fn cpu_set_opmask(id: u32, reg: u32, val: u64);
+
+    fn cpu_get_tilecfg(
+        id: u32,
+        palette_id: *mut u8,
+        start_row: *mut u8,
+        colsb: *mut u16,
+        rows: *mut u8,
+    );
+    fn cpu_set_tilecfg(id: u32, palette_id: u8, start_row: u8, colsb: *const u16, rows: *const u8);
+    fn cpu_get_tile(id: u32, reg: u32, val: *mut u64);
+    fn cpu_set_tile(id: u32, reg: u32, val: *const u64);
+
     fn cpu_get_cpu_mode(id: u32) -> u32;
+    fn cpu_get_cpl(id: u32) -> u32;
+    fn cpu_get_icount(id: u32) -> u64;
+    fn cpu_get_activity_state(id: u32) -> u32;
+    fn cpu_set_activity_state(id: u32, state: u32);
 
     /// Bail out of the cpu eval loop
     ///
@@ -165,9 +209,14 @@ unsafe extern "C" {
 
     /// Return the current killbit status
     pub(crate) fn cpu_killbit(id: u32) -> u32;
-    fn cpu_set_killbit(id: u32);
+    pub(crate) fn cpu_set_killbit(id: u32);
     fn cpu_clear_killbit(id: u32);
     pub(crate) fn cpu_exception(id: u32, vector: u32, error: u16) -> !;
+    pub(crate) fn cpu_in_guest(id: u32) -> bool;
+
+    fn cpu_rdmsr(id: u32, index: u32, val: *mut u64) -> bool;
+    fn cpu_wrmsr(id: u32, index: u32, val: u64) -> bool;
+    fn cpu_cpuid(id: u32, leaf: u32, subleaf: u32, r: *mut u32);
 }
 
 enum GpRegs {
@@ -221,6 +270,26 @@ pub struct Zmm {
     pub q: [u64; 8],
 }
 
+/// The AMX tile configuration, as loaded by LDTILECFG
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(C)]
+pub struct TileConfig {
+    pub palette_id: u8,
+    pub start_row: u8,
+    /// The bytes per row of each tile
+    pub colsb: [u16; 8],
+    pub rows: [u8; 8],
+}
+
+/// An AMX tile register, 16 rows of 64 bytes
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(C)]
+pub struct Tile {
+    pub rows: [Zmm; 16],
+}
+
 #[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(C)]
@@ -240,6 +309,17 @@ pub struct Seg {
     pub attr: u16,
 }
 
+// `cpu/cpu.h`/`BX_ACTIVITY_STATE_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ActivityState {
+    Active = 0,
+    Hlt = 1,
+    Shutdown = 2,
+    WaitForSipi = 3,
+    Mwait = 4,
+    MwaitIf = 5,
+}
+
 // `cpu/cpu.h`/`BxCpuMode`
 pub enum Mode {
     Ia32Real = 0,       // CR0.PE=0
@@ -249,23 +329,153 @@ pub enum Mode {
     Ia32Long64 = 4,     // EFER.LMA = 1, CR0.PE=1, CS.L=1
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MsrError {
+    /// The access would raise #GP, e.g. an unknown MSR or a reserved bit set
+    GeneralProtection(u32),
+}
+
+impl fmt::Display for MsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x?}", self)
+    }
+}
+
+impl Error for MsrError {
+    fn description(&self) -> &str {
+        "msr access error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum RunState {
     Go,
     Stop,
 }
 
+/// A BX_PANIC or BX_FATAL raised by bochs while the cpu was running
+///
+/// The cpu is stopped wherever bochs gave up, possibly in the middle of an
+/// instruction, so its state should be restored (e.g. from a snapshot)
+/// before it is run again.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct EmulatorPanic {
+    pub message: String,
+    /// BX_FATAL rather than BX_PANIC
+    pub fatal: bool,
+}
+
+impl fmt::Display for EmulatorPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.fatal { "fatal" } else { "panic" };
+
+        write!(f, "bochs {}: {}", kind, self.message)
+    }
+}
+
+impl Error for EmulatorPanic {
+    fn description(&self) -> &str {
+        "bochs panic"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+/// Why `CpuRun::run` returned
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum RunExit {
+    /// A hook stopped the cpu, e.g. with `Cpu::set_run_state`
+    Stop,
+    /// The missing page handler returned `MissingPage::Stop` for the page
+    /// at `gpa`
+    MissingPage { gpa: PhyAddress },
+    /// The guest executed HLT with `CpuRun::stop_on_hlt` enabled and no
+    /// hook redirected it. The cpu is left halted, see
+    /// `Cpu::set_activity_state`.
+    Hlt,
+    /// A triple fault, where `vector` is the exception which could not be
+    /// delivered
+    Shutdown { vector: u32 },
+    /// bochs raised a BX_PANIC or BX_FATAL
+    Panic(EmulatorPanic),
+    /// The instruction budget set with `CpuRun::budget` ran out
+    Budget,
+    /// The time set with `CpuRun::timeout` passed
+    Timeout,
+}
+
+/// What a single `CpuRun::step` did
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum StepResult {
+    /// One instruction, or one iteration of a REP string instruction,
+    /// completed and execution continues at `rip`
+    Executed { rip: Address },
+    /// An exception was delivered, and execution continues at the handler
+    /// at `rip`
+    Exception {
+        vector: u32,
+        error_code: u32,
+        rip: Address,
+    },
+    /// An interrupt (INT n or external) was taken, and execution continues
+    /// at the handler at `rip`
+    Interrupt { vector: u32, rip: Address },
+    /// The cpu executed HLT or MWAIT, or was already halted, and is waiting
+    /// for an interrupt. `rip` is the instruction it will resume at.
+    Halted { rip: Address },
+    /// The cpu stopped for some other reason before the step completed
+    Exit(RunExit),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum StepEvent {
+    Exception(u32, u32),
+    Interrupt(u32),
+}
+
+#[derive(Copy, Clone, Default, Eq, PartialEq, Hash, Debug)]
+struct StepState {
+    started: bool,
+    done: bool,
+    halted: bool,
+    event: Option<StepEvent>,
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub(crate) struct Tracking {
+    live: bool,
     seed: u64,
     pub(crate) state: RunState,
+    exit: Option<RunExit>,
+    icount_limit: Option<u64>,
+    deadline: Option<Instant>,
+    deadline_checks: u32,
+    stop_on_hlt: bool,
+    last_exception: Option<u32>,
+    nested_exceptions: u32,
+    step: Option<StepState>,
 }
 
 impl Default for Tracking {
     fn default() -> Self {
         Self {
+            live: false,
             seed: 0,
             state: RunState::Stop,
+            exit: None,
+            icount_limit: None,
+            deadline: None,
+            deadline_checks: 0,
+            stop_on_hlt: false,
+            last_exception: None,
+            nested_exceptions: 0,
+            step: None,
         }
     }
 }
@@ -288,6 +498,181 @@ pub(crate) unsafe fn set_run_state(id: u32, rs: RunState) {
     }
 }
 
+// the cpu currently inside cpu_loop, which is where bochs panics get
+// delivered
+static RUNNING: SyncUnsafeCell<Option<u32>> = SyncUnsafeCell::new(None);
+
+pub(crate) fn running_cpu() -> Option<u32> {
+    unsafe { *ptr_to_ref(RUNNING.0.get()) }
+}
+
+unsafe fn set_running_cpu(id: Option<u32>) {
+    unsafe {
+        *ptr_to_ref_mut(RUNNING.0.get()) = id;
+    }
+}
+
+pub(crate) unsafe fn stop_on_hlt(id: u32) -> bool {
+    unsafe { cpu_tracking(id).stop_on_hlt }
+}
+
+/// Stop the cpu for `exit`, unless it is already stopping for another reason
+pub(crate) unsafe fn stop_for(id: u32, exit: RunExit) {
+    unsafe {
+        let t = cpu_tracking(id);
+
+        if t.exit.is_none() {
+            t.exit = Some(exit);
+        }
+
+        set_run_state(id, RunState::Stop);
+        cpu_set_killbit(id);
+    }
+}
+
+// reading the clock costs about as much as executing an instruction, so the
+// deadline is only checked every this many ticks
+const DEADLINE_INTERVAL: u32 = 0x1000;
+
+/// Finish a step, or stop the cpu if the run budget has been reached, before
+/// its next instruction
+pub(crate) unsafe fn check_run_limits(id: u32) {
+    unsafe {
+        let t = cpu_tracking(id);
+
+        if let Some(s) = t.step.as_mut() {
+            if s.started {
+                s.done = true;
+                stop_for(id, RunExit::Stop);
+                cpu_bail(id)
+            }
+
+            s.started = true;
+        }
+
+        // countdown_event sees the budget as well, but ticks may lag behind
+        // the instruction count, so this is what makes it exact
+        if let Some(limit) = t.icount_limit
+            && cpu_get_icount(id) >= limit
+        {
+            stop_for(id, RunExit::Budget);
+            cpu_bail(id)
+        }
+    }
+}
+
+// bochs calls countdownEvent from tickn, which is reached both between
+// instructions and while the cpu sleeps in HLT, where before_execution never
+// fires
+#[unsafe(no_mangle)]
+extern "C-unwind" fn countdown_event() {
+    let Some(id) = running_cpu() else {
+        return;
+    };
+
+    unsafe {
+        let t = cpu_tracking(id);
+
+        // a step which put the cpu to sleep is done, as nothing will execute
+        // until an interrupt arrives
+        if let Some(s) = t.step.as_mut()
+            && cpu_get_activity_state(id) != ActivityState::Active as u32
+        {
+            s.done = true;
+            s.halted = true;
+            stop_for(id, RunExit::Stop);
+        }
+
+        // bochs returns from cpu_loop at the next instruction boundary, or
+        // leaves the HLT loop, once the killbit is set
+        if let Some(limit) = t.icount_limit
+            && cpu_get_icount(id) >= limit
+        {
+            stop_for(id, RunExit::Budget);
+        }
+
+        if let Some(deadline) = t.deadline {
+            t.deadline_checks = t.deadline_checks.wrapping_add(1);
+
+            if t.deadline_checks & (DEADLINE_INTERVAL - 1) == 0 && Instant::now() >= deadline {
+                stop_for(id, RunExit::Timeout);
+            }
+        }
+    }
+}
+
+// an exception or interrupt delivered outside of an instruction, e.g. a
+// pending interrupt or a fault on fetch, is a step of its own
+
+pub(crate) unsafe fn step_exception(id: u32, vector: u32, error_code: u32) {
+    unsafe {
+        if let Some(s) = cpu_tracking(id).step.as_mut() {
+            s.started = true;
+            s.event = Some(StepEvent::Exception(vector, error_code));
+        }
+    }
+}
+
+pub(crate) unsafe fn step_interrupt(id: u32, vector: u32) {
+    unsafe {
+        if let Some(s) = cpu_tracking(id).step.as_mut() {
+            s.started = true;
+
+            // exceptions are delivered through the interrupt path as well
+            if !matches!(s.event, Some(StepEvent::Exception(..))) {
+                s.event = Some(StepEvent::Interrupt(vector));
+            }
+        }
+    }
+}
+
+// bochs checks for the stop after the iteration, and rewinds RIP to the
+// start of the instruction if there are iterations left
+pub(crate) unsafe fn step_repeat_iteration(id: u32) {
+    unsafe {
+        if let Some(s) = cpu_tracking(id).step.as_mut() {
+            s.done = true;
+            stop_for(id, RunExit::Stop);
+        }
+    }
+}
+
+// exceptions raised since the start of the current instruction, which is
+// how a triple fault is told apart from any other bochs panic
+
+pub(crate) unsafe fn record_exception(id: u32, vector: u32) {
+    unsafe {
+        let t = cpu_tracking(id);
+
+        t.last_exception = Some(vector);
+        t.nested_exceptions += 1;
+    }
+}
+
+pub(crate) unsafe fn clear_exceptions(id: u32) {
+    unsafe {
+        cpu_tracking(id).nested_exceptions = 0;
+    }
+}
+
+pub(crate) unsafe fn cpu_panic(id: u32, p: EmulatorPanic) -> ! {
+    unsafe {
+        // bochs panics on a triple fault unless it is configured to reset,
+        // which bochscpu cannot do. By then the instruction has raised three
+        // exceptions, or the cpu has already been shut down.
+        let t = cpu_tracking(id);
+        let shutdown = t.nested_exceptions >= 3
+            || cpu_get_activity_state(id) == ActivityState::Shutdown as u32;
+
+        match t.last_exception {
+            Some(vector) if shutdown => stop_for(id, RunExit::Shutdown { vector }),
+            _ => stop_for(id, RunExit::Panic(p)),
+        }
+
+        cpu_bail(id)
+    }
+}
+
 unsafe fn seed(id: u32) -> u64 {
     unsafe { cpu_tracking(id).seed }
 }
@@ -313,19 +698,73 @@ extern "C" fn bochscpu_rand(id: u32) -> u64 {
 
 pub struct CpuRun<'a> {
     cpu: &'a Cpu,
+    budget: Option<u64>,
+    timeout: Option<Duration>,
+    stop_on_hlt: bool,
 }
 
 impl<'a> CpuRun<'a> {
     pub fn new(cpu: &'a Cpu) -> Self {
-        Self { cpu }
+        Self {
+            cpu,
+            budget: None,
+            timeout: None,
+            stop_on_hlt: false,
+        }
     }
 
-    pub unsafe fn run(self) -> RunState {
+    /// Stop with `RunExit::Budget` once `n` instructions have executed
+    pub fn budget(mut self, n: u64) -> Self {
+        self.budget = Some(n);
+        self
+    }
+
+    /// Stop with `RunExit::Timeout` once `d` has passed since `run` was
+    /// called. The deadline is checked every few thousand ticks of the bochs
+    /// clock, which keeps ticking while the guest is halted.
+    pub fn timeout(mut self, d: Duration) -> Self {
+        self.timeout = Some(d);
+        self
+    }
+
+    /// Stop with `RunExit::Hlt` when the guest executes HLT
+    ///
+    /// Off by default, in which case the cpu goes to sleep as usual and only
+    /// a hook can end the run.
+    pub fn stop_on_hlt(mut self, stop: bool) -> Self {
+        self.stop_on_hlt = stop;
+        self
+    }
+
+    /// Run at most `n` instructions
+    ///
+    /// # Safety
+    ///
+    /// The same as `run`: the cpu must not already be running, and every hook
+    /// registered with `register` must still be alive.
+    pub unsafe fn run_for(self, n: u64) -> RunExit {
+        unsafe { self.budget(n).run() }
+    }
+
+    /// Run until something stops the cpu, and return why
+    pub unsafe fn run(self) -> RunExit {
         unsafe {
             set_hook_event(self.cpu.handle, None);
 
+            let t = cpu_tracking(self.cpu.handle);
+            t.exit = None;
+            t.last_exception = None;
+            t.nested_exceptions = 0;
+            t.icount_limit = self
+                .budget
+                .map(|n| cpu_get_icount(self.cpu.handle).saturating_add(n));
+            t.deadline = self.timeout.map(|d| Instant::now() + d);
+            t.stop_on_hlt = self.stop_on_hlt;
+
             self.cpu.set_run_state(RunState::Go);
 
+            set_running_cpu(Some(self.cpu.handle));
+
             while cpu_killbit(self.cpu.handle) == 0 {
                 match run_state(self.cpu.handle) {
                     RunState::Stop => break,
@@ -333,7 +772,63 @@ impl<'a> CpuRun<'a> {
                 }
             }
 
-            self.cpu.run_state()
+            set_running_cpu(None);
+
+            let t = cpu_tracking(self.cpu.handle);
+            t.icount_limit = None;
+            t.deadline = None;
+            t.stop_on_hlt = false;
+
+            t.exit.take().unwrap_or(RunExit::Stop)
+        }
+    }
+
+    /// Execute a single instruction, or a single iteration of a REP string
+    /// instruction
+    ///
+    /// Hooks fire as they would during `run`. A budget or timeout still
+    /// applies, and like any other reason for stopping early is returned as
+    /// `StepResult::Exit`.
+    ///
+    /// Stepping HLT returns `StepResult::Halted` once the cpu is asleep,
+    /// whether or not `stop_on_hlt` is set, and so does stepping a cpu which
+    /// is already halted, without executing anything.
+    ///
+    /// # Safety
+    ///
+    /// The same as `run`: the cpu must not already be running, and every hook
+    /// registered with `register` must still be alive.
+    pub unsafe fn step(self) -> StepResult {
+        unsafe {
+            let cpu = self.cpu;
+
+            if cpu_get_activity_state(cpu.handle) != ActivityState::Active as u32 {
+                return StepResult::Halted { rip: cpu.rip() };
+            }
+
+            cpu_tracking(cpu.handle).step = Some(StepState::default());
+
+            let exit = self.run();
+
+            let s = cpu_tracking(cpu.handle).step.take().unwrap();
+            let rip = cpu.rip();
+
+            match (exit, s.event) {
+                (RunExit::Hlt, _) => StepResult::Halted { rip },
+                (RunExit::Stop, _) if s.halted => StepResult::Halted { rip },
+                (RunExit::Stop, Some(StepEvent::Exception(vector, error_code))) if s.done => {
+                    StepResult::Exception {
+                        vector,
+                        error_code,
+                        rip,
+                    }
+                }
+                (RunExit::Stop, Some(StepEvent::Interrupt(vector))) if s.done => {
+                    StepResult::Interrupt { vector, rip }
+                }
+                (RunExit::Stop, None) if s.done => StepResult::Executed { rip },
+                (exit, _) => StepResult::Exit(exit),
+            }
         }
     }
 
@@ -344,6 +839,63 @@ impl<'a> CpuRun<'a> {
             self
         }
     }
+
+    /// Return results from `table` for the CPUID leaves it contains, and
+    /// call `Hooks::cpuid_result` for every CPUID the guest executes
+    ///
+    /// CPUID is completed without running the bochs instruction handler,
+    /// except inside a VMX or SVM guest, where it is left to bochs so the
+    /// hypervisor still sees its exit, and neither the table nor the hook
+    /// apply. CPUID faulting is not honored: ring 3 CPUID returns a result
+    /// even when MISC_FEATURES_ENABLES asks for #GP.
+    ///
+    /// # Safety
+    ///
+    /// The table is shared by every cpu and only removed when this `CpuRun`
+    /// is dropped, so no other cpu may be running in the meantime.
+    pub unsafe fn cpuid_table(self, table: CpuidTable) -> Self {
+        unsafe {
+            hook::set_cpuid_table(table);
+
+            self
+        }
+    }
+
+    /// Call `Hooks::cpuid_result` for every CPUID the guest executes,
+    /// without a table of results
+    ///
+    /// CPUID is completed without running the bochs instruction handler,
+    /// except inside a VMX or SVM guest, where it is left to bochs so the
+    /// hypervisor still sees its exit, and neither the table nor the hook
+    /// apply. CPUID faulting is not honored: ring 3 CPUID returns a result
+    /// even when MISC_FEATURES_ENABLES asks for #GP.
+    ///
+    /// # Safety
+    ///
+    /// The interception is shared by every cpu and only removed when this
+    /// `CpuRun` is dropped, so no other cpu may be running in the meantime.
+    pub unsafe fn intercept_cpuid(self) -> Self {
+        unsafe {
+            hook::intercept_cpuid();
+
+            self
+        }
+    }
+
+    /// Call `Hooks::rdmsr` before the guest reads `msr`
+    ///
+    /// # Safety
+    ///
+    /// The set of intercepted MSRs is shared by every cpu and only cleared
+    /// when this `CpuRun` is dropped, so no other cpu may be running in the
+    /// meantime.
+    pub unsafe fn intercept_rdmsr(self, msr: u32) -> Self {
+        unsafe {
+            hook::intercept_rdmsr(msr);
+
+            self
+        }
+    }
 }
 
 impl<'a> Drop for CpuRun<'a> {
@@ -352,6 +904,15 @@ impl<'a> Drop for CpuRun<'a> {
     }
 }
 
+// bochs reads its configuration when a cpu is constructed, so this is what
+// stops it being changed underneath live cpus
+pub(crate) fn live_cpus() -> usize {
+    unsafe { ptr_to_ref(CPU_TRACKING.0.get()) }
+        .iter()
+        .filter(|t| t.live)
+        .count()
+}
+
 pub struct Cpu {
     handle: u32,
 }
@@ -360,6 +921,7 @@ impl Cpu {
     pub unsafe fn new(id: u32) -> Self {
         unsafe {
             cpu_new(id);
+            cpu_tracking(id).live = true;
 
             Self { handle: id }
         }
@@ -393,10 +955,34 @@ impl Cpu {
 
     pub unsafe fn delete(self) {
         unsafe {
+            mem::bind_memory(self.handle, ptr::null_mut());
             cpu_delete(self.handle);
+            cpu_tracking(self.handle).live = false;
         }
     }
 
+    /// Bind this cpu to a guest physical address space
+    ///
+    /// All physical memory accesses made by this cpu will resolve through
+    /// `m` instead of the default instance.
+    ///
+    /// # Safety
+    ///
+    /// `m` must stay alive until the cpu is rebound, cleared, or deleted, and
+    /// the cpu must not be running.
+    pub unsafe fn set_memory(&self, m: &mut GuestMemory) {
+        unsafe { mem::bind_memory(self.handle, m) }
+    }
+
+    /// Rebind this cpu to the default guest physical address space
+    ///
+    /// # Safety
+    ///
+    /// The cpu must not be running.
+    pub unsafe fn clear_memory(&self) {
+        unsafe { mem::bind_memory(self.handle, ptr::null_mut()) }
+    }
+
     pub unsafe fn prepare(&self) -> CpuRun<'_> {
         CpuRun::new(self)
     }
@@ -569,6 +1155,27 @@ impl Cpu {
                     self.zmm(30),
                     self.zmm(31),
                 ],
+                opmask: [
+                    self.opmask(0),
+                    self.opmask(1),
+                    self.opmask(2),
+                    self.opmask(3),
+                    self.opmask(4),
+                    self.opmask(5),
+                    self.opmask(6),
+                    self.opmask(7),
+                ],
+                tilecfg: self.tilecfg(),
+                tiles: [
+                    self.tile(0),
+                    self.tile(1),
+                    self.tile(2),
+                    self.tile(3),
+                    self.tile(4),
+                    self.tile(5),
+                    self.tile(6),
+                    self.tile(7),
+                ],
                 mxcsr: self.mxcsr(),
                 mxcsr_mask: self.mxcsr_mask(),
 
@@ -576,6 +1183,10 @@ impl Cpu {
                 fpsw: self.fp_sw(),
                 fptw: self.fp_tw(),
                 fpop: self.fp_op(),
+                fpip: self.fp_ip(),
+                fpdp: self.fp_dp(),
+                fpcs: self.fp_cs(),
+                fpds: self.fp_ds(),
                 fpst: [
                     self.fp_st(0),
                     self.fp_st(1),
@@ -594,6 +1205,10 @@ impl Cpu {
                 pl2_ssp: self.pl2_ssp(),
                 pl3_ssp: self.pl3_ssp(),
                 interrupt_ssp_table: self.interrupt_ssp_table(),
+
+                xss: self.xss(),
+                pkru: self.pkru(),
+                pkrs: self.pkrs(),
             }
         }
     }
@@ -671,9 +1286,20 @@ impl Cpu {
             self.set_pl3_ssp(s.pl3_ssp);
             self.set_interrupt_ssp_table(s.interrupt_ssp_table);
 
+            self.set_xss(s.xss);
+            self.set_pkru(s.pkru);
+            self.set_pkrs(s.pkrs);
+
             for (ii, z) in (s.zmm).iter().enumerate() {
                 self.set_zmm(ii, *z);
             }
+            for (ii, k) in (s.opmask).iter().enumerate() {
+                self.set_opmask(ii, *k);
+            }
+            self.set_tilecfg(&s.tilecfg);
+            for (ii, t) in (s.tiles).iter().enumerate() {
+                self.set_tile(ii, t);
+            }
             self.set_mxcsr(s.mxcsr);
             self.set_mxcsr_mask(s.mxcsr_mask);
 
@@ -681,6 +1307,10 @@ impl Cpu {
             self.set_fp_sw(s.fpsw);
             self.set_fp_tw(s.fptw);
             self.set_fp_op(s.fpop);
+            self.set_fp_ip(s.fpip);
+            self.set_fp_dp(s.fpdp);
+            self.set_fp_cs(s.fpcs);
+            self.set_fp_ds(s.fpds);
 
             for (ii, f) in (s.fpst).iter().enumerate() {
                 self.set_fp_st(ii, *f);
@@ -699,6 +1329,23 @@ impl Cpu {
         }
     }
 
+    /// From `Hooks::rdmsr`, complete the RDMSR with `v` instead of the value
+    /// bochs would return. From `Hooks::wrmsr`, write `v` instead of the
+    /// value the guest wrote.
+    pub unsafe fn set_msr_value(&self, v: u64) {
+        unsafe {
+            *msr_override(self.handle) = Some(MsrOverride::Value(v));
+        }
+    }
+
+    /// From `Hooks::rdmsr` or `Hooks::wrmsr`, complete the instruction
+    /// without any effect
+    pub unsafe fn skip_msr(&self) {
+        unsafe {
+            *msr_override(self.handle) = Some(MsrOverride::Skip);
+        }
+    }
+
     pub unsafe fn set_exception(&self, vector: u32, error: Option<u16>) {
         unsafe {
             set_hook_event(self.handle, Some(HookEvent::Exception(vector, error)));
@@ -1218,6 +1865,23 @@ impl Cpu {
         unsafe { cpu_set_mode(self.handle) }
     }
 
+    /// Drop every cached linear to physical translation
+    ///
+    /// bochs caches host pointers to guest pages in its TLB, so this must be
+    /// called after unmapping or remapping guest physical pages.
+    pub unsafe fn flush_tlb(&self) {
+        unsafe { cpu_flush_tlb(self.handle) }
+    }
+
+    /// Drop every decoded instruction trace
+    ///
+    /// Writes made by the host directly to guest memory bypass bochs'
+    /// self-modifying code detection, so this must be called after the host
+    /// changes guest code.
+    pub unsafe fn flush_icache(&self) {
+        unsafe { cpu_flush_icache(self.handle) }
+    }
+
     pub unsafe fn xcr0(&self) -> u32 {
         unsafe { cpu_get_xcr0(self.handle) }
     }
@@ -1380,6 +2044,75 @@ impl Cpu {
         unsafe { cpu_set_interrupt_ssp_table(self.handle, v) }
     }
 
+    pub unsafe fn xss(&self) -> u64 {
+        unsafe { cpu_get_xss(self.handle) }
+    }
+
+    pub unsafe fn set_xss(&self, v: u64) {
+        unsafe { cpu_set_xss(self.handle, v) }
+    }
+
+    pub unsafe fn pkru(&self) -> u32 {
+        unsafe { cpu_get_pkru(self.handle) }
+    }
+
+    pub unsafe fn set_pkru(&self, v: u32) {
+        unsafe { cpu_set_pkru(self.handle, v) }
+    }
+
+    pub unsafe fn pkrs(&self) -> u32 {
+        unsafe { cpu_get_pkrs(self.handle) }
+    }
+
+    pub unsafe fn set_pkrs(&self, v: u32) {
+        unsafe { cpu_set_pkrs(self.handle, v) }
+    }
+
+    /// Read any MSR the way the RDMSR instruction would
+    ///
+    /// Unknown MSRs read as 0 rather than failing while the
+    /// `cpu.ignore_bad_msrs` parameter is set.
+    pub unsafe fn rdmsr(&self, index: u32) -> Result<u64, MsrError> {
+        unsafe {
+            let mut v = 0;
+
+            if cpu_rdmsr(self.handle, index, &mut v) {
+                Ok(v)
+            } else {
+                Err(MsrError::GeneralProtection(index))
+            }
+        }
+    }
+
+    /// Write any MSR the way the WRMSR instruction would, including its side
+    /// effects. `Hooks::wrmsr` is not called, as bochs only reports writes
+    /// done by the instruction.
+    pub unsafe fn wrmsr(&self, index: u32, v: u64) -> Result<(), MsrError> {
+        unsafe {
+            if cpu_wrmsr(self.handle, index, v) {
+                Ok(())
+            } else {
+                Err(MsrError::GeneralProtection(index))
+            }
+        }
+    }
+
+    /// What bochs returns for CPUID with `leaf` in eax and `subleaf` in ecx,
+    /// ignoring any `CpuidTable` installed for the run
+    pub unsafe fn cpuid(&self, leaf: u32, subleaf: u32) -> CpuidResult {
+        unsafe {
+            let mut r = [0u32; 4];
+            cpu_cpuid(self.handle, leaf, subleaf, r.as_mut_ptr());
+
+            CpuidResult {
+                eax: r[0],
+                ebx: r[1],
+                ecx: r[2],
+                edx: r[3],
+            }
+        }
+    }
+
     // TODO mtrrphys?
 
     // zmm
@@ -1409,6 +2142,64 @@ impl Cpu {
         unsafe { cpu_set_mxcsr(self.handle, v) }
     }
 
+    pub unsafe fn opmask(&self, idx: usize) -> u64 {
+        unsafe {
+            assert!(idx < 8);
+            cpu_get_opmask(self.handle, idx as _)
+        }
+    }
+
+    pub unsafe fn set_opmask(&self, idx: usize, v: u64) {
+        unsafe {
+            assert!(idx < 8);
+            cpu_set_opmask(self.handle, idx as _, v)
+        }
+    }
+
+    pub unsafe fn tilecfg(&self) -> TileConfig {
+        unsafe {
+            let mut v = TileConfig::default();
+            cpu_get_tilecfg(
+                self.handle,
+                &mut v.palette_id,
+                &mut v.start_row,
+                v.colsb.as_mut_ptr(),
+                v.rows.as_mut_ptr(),
+            );
+
+            v
+        }
+    }
+
+    pub unsafe fn set_tilecfg(&self, v: &TileConfig) {
+        unsafe {
+            cpu_set_tilecfg(
+                self.handle,
+                v.palette_id,
+                v.start_row,
+                v.colsb.as_ptr(),
+                v.rows.as_ptr(),
+            )
+        }
+    }
+
+    pub unsafe fn tile(&self, idx: usize) -> Tile {
+        unsafe {
+            assert!(idx < 8);
+            let mut v = Tile::default();
+            cpu_get_tile(self.handle, idx as _, v.rows.as_mut_ptr() as *mut u64);
+
+            v
+        }
+    }
+
+    pub unsafe fn set_tile(&self, idx: usize, v: &Tile) {
+        unsafe {
+            assert!(idx < 8);
+            cpu_set_tile(self.handle, idx as _, v.rows.as_ptr() as *const u64)
+        }
+    }
+
     pub unsafe fn mxcsr_mask(&self) -> u32 {
         unsafe { cpu_get_mxcsr_mask(self.handle) }
     }
@@ -1451,6 +2242,38 @@ impl Cpu {
         unsafe { cpu_set_fp_op(self.handle, v) }
     }
 
+    pub unsafe fn fp_ip(&self) -> u64 {
+        unsafe { cpu_get_fp_ip(self.handle) }
+    }
+
+    pub unsafe fn set_fp_ip(&self, v: u64) {
+        unsafe { cpu_set_fp_ip(self.handle, v) }
+    }
+
+    pub unsafe fn fp_dp(&self) -> u64 {
+        unsafe { cpu_get_fp_dp(self.handle) }
+    }
+
+    pub unsafe fn set_fp_dp(&self, v: u64) {
+        unsafe { cpu_set_fp_dp(self.handle, v) }
+    }
+
+    pub unsafe fn fp_cs(&self) -> u16 {
+        unsafe { cpu_get_fp_cs(self.handle) }
+    }
+
+    pub unsafe fn set_fp_cs(&self, v: u16) {
+        unsafe { cpu_set_fp_cs(self.handle, v) }
+    }
+
+    pub unsafe fn fp_ds(&self) -> u16 {
+        unsafe { cpu_get_fp_ds(self.handle) }
+    }
+
+    pub unsafe fn set_fp_ds(&self, v: u16) {
+        unsafe { cpu_set_fp_ds(self.handle, v) }
+    }
+
     pub unsafe fn fp_st(&self, idx: usize) -> Float80 {
         unsafe {
             assert!(idx < 8);
@@ -1468,6 +2291,43 @@ impl Cpu {
         }
     }
 
+    pub unsafe fn cpl(&self) -> u32 {
+        unsafe { cpu_get_cpl(self.handle) }
+    }
+
+    /// Execute a single instruction without any hooks, see `CpuRun::step`
+    ///
+    /// # Safety
+    ///
+    /// The cpu must not already be running. Hooks are shared by every cpu and
+    /// cleared when the step ends, so no other cpu may be running with hooks.
+    pub unsafe fn step(&self) -> StepResult {
+        unsafe { CpuRun::new(self).step() }
+    }
+
+    /// The number of instructions this cpu has executed
+    pub unsafe fn icount(&self) -> u64 {
+        unsafe { cpu_get_icount(self.handle) }
+    }
+
+    pub unsafe fn activity_state(&self) -> ActivityState {
+        match unsafe { cpu_get_activity_state(self.handle) } {
+            0 => ActivityState::Active,
+            1 => ActivityState::Hlt,
+            2 => ActivityState::Shutdown,
+            3 => ActivityState::WaitForSipi,
+            4 => ActivityState::Mwait,
+            5 => ActivityState::MwaitIf,
+            v => panic!("unknown value {v} in activity_state"),
+        }
+    }
+
+    /// e.g. `ActivityState::Active` to resume a cpu after `RunExit::Hlt`
+    /// without an interrupt
+    pub unsafe fn set_activity_state(&self, state: ActivityState) {
+        unsafe { cpu_set_activity_state(self.handle, state as u32) }
+    }
+
     pub unsafe fn cpu_mode(&self) -> Mode {
         match unsafe { cpu_get_cpu_mode(self.handle) } {
             0 => Mode::Ia32Real,
@@ -1497,4 +2357,104 @@ impl Cpu {
     pub fn long64_mode(&self) -> bool {
         matches!(unsafe { self.cpu_mode() }, Mode::Ia32Long64)
     }
+
+    /// The paging mode selected by the current CR0, CR4 and EFER
+    pub unsafe fn paging_mode(&self) -> PagingMode {
+        unsafe { PagingMode::from_regs(self.cr0(), self.cr4(), self.efer()) }
+    }
+
+    /// Translate a linear address with the cpu's current paging mode and
+    /// CR3, through the guest memory bound to it
+    pub unsafe fn virt_translate_checked(&self, gva: Address) -> Result<PhyAddress, VirtMemError> {
+        unsafe {
+            mem::cpu_memory(self.handle).virt_translate_checked(self.paging_mode(), self.cr3(), gva)
+        }
+    }
+
+    pub unsafe fn virt_read_slice_checked(
+        &self,
+        gva: Address,
+        buf: &mut [u8],
+    ) -> Result<(), VirtMemError> {
+        unsafe {
+            mem::cpu_memory(self.handle).virt_read_slice_checked(
+                self.paging_mode(),
+                self.cr3(),
+                gva,
+                buf,
+            )
+        }
+    }
+
+    pub unsafe fn virt_write_checked(&self, gva: Address, buf: &[u8]) -> Result<(), VirtMemError> {
+        unsafe {
+            mem::cpu_memory(self.handle).virt_write_checked(
+                self.paging_mode(),
+                self.cr3(),
+                gva,
+                buf,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // bochs is not thread safe, so only one test may use a cpu at a time
+    static BOCHS: Mutex<()> = Mutex::new(());
+
+    #[repr(C, align(4096))]
+    struct Page([u8; 0x1000]);
+
+    #[test]
+    fn step_hlt() {
+        let _guard = BOCHS.lock().unwrap();
+
+        // HLT everywhere
+        let mut page = Page([0xf4; 0x1000]);
+        let mut mem = GuestMemory::new();
+
+        unsafe {
+            mem.page_insert(0, page.0.as_mut_ptr());
+
+            let cpu = Cpu::new(0);
+            cpu.set_memory(&mut mem);
+            cpu.set_state(&StateBuilder::protected32_flat().rip(0x100).build());
+
+            assert_eq!(cpu.step(), StepResult::Halted { rip: 0x101 });
+            assert_eq!(cpu.step(), StepResult::Halted { rip: 0x101 });
+
+            cpu.set_activity_state(ActivityState::Active);
+            assert_eq!(cpu.step(), StepResult::Halted { rip: 0x102 });
+
+            cpu.delete();
+        }
+    }
+
+    #[test]
+    fn timeout_while_halted() {
+        let _guard = BOCHS.lock().unwrap();
+
+        let mut page = Page([0xf4; 0x1000]);
+        let mut mem = GuestMemory::new();
+
+        unsafe {
+            mem.page_insert(0, page.0.as_mut_ptr());
+
+            let cpu = Cpu::new(0);
+            cpu.set_memory(&mut mem);
+            cpu.set_state(&StateBuilder::protected32_flat().rip(0x100).build());
+
+            let exit = CpuRun::new(&cpu).timeout(Duration::from_millis(10)).run();
+
+            assert_eq!(exit, RunExit::Timeout);
+            assert_eq!(cpu.rip(), 0x101);
+
+            cpu.delete();
+        }
+    }
 }
@@ -1,7 +1,7 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::cpu::{Float80, GlobalSeg, Seg, Zmm};
+use crate::cpu::{Float80, GlobalSeg, Seg, Tile, TileConfig, Zmm};
 
 #[derive(Clone, PartialEq, Eq, Debug, Default, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -57,13 +57,20 @@ pub struct State {
     pub xcr0: u32,
 
     pub zmm: [Zmm; 32],
+    pub opmask: [u64; 8],
+    // all zero, and ignored when set, if bochs is built without AMX or the
+    // cpu model does not support it
+    pub tilecfg: TileConfig,
+    pub tiles: [Tile; 8],
 
-    // TODO fp regs
-    // TODO fp control info
     pub fpcw: u16,
     pub fpsw: u16,
     pub fptw: u16,
     pub fpop: u16,
+    pub fpip: u64,
+    pub fpdp: u64,
+    pub fpcs: u16,
+    pub fpds: u16,
     pub fpst: [Float80; 8],
 
     // TODO xmm control info
@@ -91,4 +98,9 @@ pub struct State {
     pub pl2_ssp: u64,
     pub pl3_ssp: u64,
     pub interrupt_ssp_table: u64,
+
+    pub xss: u64,
+    pub pkru: u32,
+    pub pkrs: u32,
+    // bochs does not model MPX
 }
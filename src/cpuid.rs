@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CpuidDumpError {
+    /// The line (1 based) is not in the `cpuid -r` format
+    Malformed(usize),
+}
+
+impl fmt::Display for CpuidDumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CpuidDumpError {
+    fn description(&self) -> &str {
+        "cpuid dump parse error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+// leaves where ecx selects a subleaf, everything else ignores ecx and is
+// stored as subleaf 0
+fn is_indexed(leaf: u32) -> bool {
+    matches!(
+        leaf,
+        0x4 | 0x7
+            | 0xb
+            | 0xd
+            | 0xf
+            | 0x10
+            | 0x12
+            | 0x14
+            | 0x17
+            | 0x18
+            | 0x1a
+            | 0x1b
+            | 0x1d
+            | 0x1e
+            | 0x1f
+            | 0x20
+            | 0x23
+            | 0x24
+            | 0x8000_001d
+            | 0x8000_0020
+            | 0x8000_0026
+    )
+}
+
+fn key(leaf: u32, subleaf: u32) -> (u32, u32) {
+    if is_indexed(leaf) {
+        (leaf, subleaf)
+    } else {
+        (leaf, 0)
+    }
+}
+
+/// CPUID results to return in place of the ones bochs computes
+///
+/// Install with `CpuRun::cpuid_table`. Leaves which are not in the table
+/// return whatever the configured bochs cpu model returns. The table is not
+/// used inside a VMX or SVM guest, and does not honor CPUID faulting, see
+/// `CpuRun::cpuid_table`.
+#[derive(Clone, Debug, Default)]
+pub struct CpuidTable {
+    leaves: BTreeMap<(u32, u32), CpuidResult>,
+}
+
+impl CpuidTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the output of `cpuid -r`
+    ///
+    /// Only the first cpu in the dump is used, as bochs presents the same
+    /// CPUID to every cpu.
+    pub fn from_dump(dump: &str) -> Result<Self, CpuidDumpError> {
+        let mut table = Self::new();
+        let mut cpus = 0;
+
+        for (n, line) in dump.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with("CPU") {
+                cpus += 1;
+
+                if cpus > 1 {
+                    break;
+                }
+
+                continue;
+            }
+
+            let (leaf, subleaf, r) = parse_line(line).ok_or(CpuidDumpError::Malformed(n + 1))?;
+
+            table.insert(leaf, subleaf, r);
+        }
+
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, leaf: u32, subleaf: u32, r: CpuidResult) {
+        self.leaves.insert(key(leaf, subleaf), r);
+    }
+
+    pub fn remove(&mut self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        self.leaves.remove(&key(leaf, subleaf))
+    }
+
+    pub fn get(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        self.leaves.get(&key(leaf, subleaf)).copied()
+    }
+
+    /// All entries as ((leaf, subleaf), result), in leaf order
+    pub fn iter(&self) -> impl Iterator<Item = ((u32, u32), CpuidResult)> + '_ {
+        self.leaves.iter().map(|(k, v)| (*k, *v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+
+    u32::from_str_radix(s, 16).ok()
+}
+
+// 0x00000007 0x00: eax=0x00000000 ebx=0x009c6fbf ecx=0x40000000 edx=0xbc000400
+fn parse_line(line: &str) -> Option<(u32, u32, CpuidResult)> {
+    let (index, regs) = line.split_once(':')?;
+
+    let mut index = index.split_whitespace();
+    let leaf = parse_hex(index.next()?)?;
+    let subleaf = parse_hex(index.next()?)?;
+
+    if index.next().is_some() {
+        return None;
+    }
+
+    let mut r = CpuidResult::default();
+    let mut seen = 0;
+
+    for reg in regs.split_whitespace() {
+        let (name, val) = reg.split_once('=')?;
+        let val = parse_hex(val)?;
+
+        let (dst, bit) = match name {
+            "eax" => (&mut r.eax, 1),
+            "ebx" => (&mut r.ebx, 2),
+            "ecx" => (&mut r.ecx, 4),
+            "edx" => (&mut r.edx, 8),
+            _ => return None,
+        };
+
+        *dst = val;
+        seen |= bit;
+    }
+
+    if seen != 0xf {
+        return None;
+    }
+
+    Some((leaf, subleaf, r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidResult {
+        CpuidResult { eax, ebx, ecx, edx }
+    }
+
+    #[test]
+    fn dump() {
+        let dump = "\
+CPU 0:
+   0x00000000 0x00: eax=0x00000016 ebx=0x756e6547 ecx=0x6c65746e edx=0x49656e69
+   0x00000007 0x00: eax=0x00000000 ebx=0x009c6fbf ecx=0x40000000 edx=0xbc000400
+   0x00000007 0x01: eax=0x00000400 ebx=0x00000000 ecx=0x00000000 edx=0x00000000
+
+CPU 1:
+   0x00000000 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004
+   0x00000001 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004
+";
+
+        let t = CpuidTable::from_dump(dump).unwrap();
+
+        // only the first cpu is used
+        assert_eq!(t.len(), 3);
+        assert_eq!(
+            t.get(0, 0),
+            Some(r(0x16, 0x756e6547, 0x6c65746e, 0x49656e69))
+        );
+        assert_eq!(t.get(1, 0), None);
+
+        assert_eq!(t.get(7, 0), Some(r(0, 0x009c6fbf, 0x40000000, 0xbc000400)));
+        assert_eq!(t.get(7, 1), Some(r(0x400, 0, 0, 0)));
+        assert_eq!(t.get(7, 2), None);
+    }
+
+    #[test]
+    fn subleaves() {
+        let mut t = CpuidTable::new();
+
+        // leaf 1 ignores ecx, so every subleaf is the same entry
+        t.insert(1, 5, r(1, 2, 3, 4));
+        assert_eq!(t.get(1, 0), Some(r(1, 2, 3, 4)));
+        assert_eq!(t.get(1, 0xffff), Some(r(1, 2, 3, 4)));
+
+        // leaf 4 is indexed by ecx
+        t.insert(4, 1, r(5, 6, 7, 8));
+        assert_eq!(t.get(4, 1), Some(r(5, 6, 7, 8)));
+        assert_eq!(t.get(4, 0), None);
+
+        assert_eq!(
+            t.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            [(1, 0), (4, 1)]
+        );
+        assert_eq!(t.remove(1, 9), Some(r(1, 2, 3, 4)));
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn malformed() {
+        let ok = "0x00000001 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004";
+
+        for line in [
+            // no separator
+            "0x00000001 0x00 eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004",
+            // missing subleaf
+            "0x00000001: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004",
+            // extra index
+            "0x00000001 0x00 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004",
+            // not hex
+            "1 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx=0x00000004",
+            "0x00000001 0x00: eax=1 ebx=0x00000002 ecx=0x00000003 edx=0x00000004",
+            // missing and unknown registers
+            "0x00000001 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003",
+            "0x00000001 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 esi=0x00000004",
+            "0x00000001 0x00: eax=0x00000001 ebx=0x00000002 ecx=0x00000003 edx",
+        ] {
+            assert_eq!(parse_line(line), None, "{}", line);
+        }
+
+        // the line number is 1 based and counts blank and CPU lines
+        let dump = format!("CPU 0:\n\n{}\n{}\n", ok, "0x00000002 0x00: garbage");
+
+        assert_eq!(
+            CpuidTable::from_dump(&dump).unwrap_err(),
+            CpuidDumpError::Malformed(4)
+        );
+    }
+}
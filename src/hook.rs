@@ -4,7 +4,14 @@ use std::mem;
 use std::slice;
 
 use crate::NUM_CPUS;
-use crate::cpu::{cpu_bail, cpu_exception};
+use crate::cpu::Cpu;
+use crate::cpu::{
+    RunExit, check_run_limits, clear_exceptions, cpu_bail, cpu_exception, cpu_in_guest,
+    record_exception, step_exception, step_interrupt, step_repeat_iteration, stop_for, stop_on_hlt,
+};
+use crate::cpuid::{CpuidResult, CpuidTable};
+use crate::mem::{FastSet64, cpu_memory};
+use crate::opcode::{instr_ilen, instr_is_cpuid, instr_is_rdmsr};
 use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref_mut};
 use crate::{Address, PhyAddress};
 
@@ -29,6 +36,54 @@ pub(crate) unsafe fn set_hook_event(id: u32, he: Option<HookEvent>) {
     }
 }
 
+// what a hook asked to do instead of executing an intercepted RDMSR or WRMSR
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum MsrOverride {
+    Value(u64),
+    Skip,
+}
+
+#[ctor]
+static MSR_OVERRIDES: SyncUnsafeCell<Vec<Option<MsrOverride>>> =
+    unsafe { SyncUnsafeCell::new(vec![None; NUM_CPUS]) };
+
+pub(crate) unsafe fn msr_override(id: u32) -> &'static mut Option<MsrOverride> {
+    unsafe { &mut ptr_to_ref_mut(MSR_OVERRIDES.0.get())[id as usize] }
+}
+
+#[ctor]
+static RDMSR_INTERCEPTS: SyncUnsafeCell<FastSet64<u32>> =
+    unsafe { SyncUnsafeCell::new(FastSet64::default()) };
+
+unsafe fn rdmsr_intercepts() -> &'static mut FastSet64<u32> {
+    unsafe { ptr_to_ref_mut(RDMSR_INTERCEPTS.0.get()) }
+}
+
+pub(crate) unsafe fn intercept_rdmsr(msr: u32) {
+    unsafe {
+        rdmsr_intercepts().insert(msr);
+    }
+}
+
+static CPUID_TABLE: SyncUnsafeCell<Option<CpuidTable>> = SyncUnsafeCell::new(None);
+
+unsafe fn cpuid_table() -> &'static mut Option<CpuidTable> {
+    unsafe { ptr_to_ref_mut(CPUID_TABLE.0.get()) }
+}
+
+pub(crate) unsafe fn set_cpuid_table(table: CpuidTable) {
+    unsafe {
+        *cpuid_table() = Some(table);
+    }
+}
+
+// an empty table intercepts CPUID without changing any leaf
+pub(crate) unsafe fn intercept_cpuid() {
+    unsafe {
+        cpuid_table().get_or_insert_with(CpuidTable::new);
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 #[repr(u32)]
 pub enum ResetSource {
@@ -266,8 +321,23 @@ pub trait Hooks {
     }
 
     fn cpuid(&mut self, _id: u32) {}
-
+    /// Only called once CPUID is intercepted with `CpuRun::intercept_cpuid`
+    /// or `CpuRun::cpuid_table`. `result` starts out as what the table, or
+    /// bochs for leaves missing from it, returns and is what the guest will
+    /// see. Not called for CPUID inside a VMX or SVM guest, which exits to
+    /// the hypervisor instead.
+    fn cpuid_result(&mut self, _id: u32, _leaf: u32, _subleaf: u32, _result: &mut CpuidResult) {}
+
+    /// Called from inside WRMSR, before the write. If the write is replaced
+    /// with `Cpu::set_msr_value` or dropped with `Cpu::skip_msr`, the
+    /// instruction ends here and `after_execution` is not called for it. bochs
+    /// still counts it in `Cpu::icount`, and so against `CpuRun::budget`.
     fn wrmsr(&mut self, _id: u32, _msr: u32, _val: u64) {}
+    /// Only called for MSRs registered with `CpuRun::intercept_rdmsr`, before
+    /// the instruction executes. Use `Cpu::set_msr_value` to supply the
+    /// result, or `Cpu::set_exception` to raise #GP instead. A read completed
+    /// this way still reaches `after_execution`, and counts in `Cpu::icount`.
+    fn rdmsr(&mut self, _id: u32, _msr: u32) {}
 
     fn vmexit(&mut self, _id: u32, _reason: u32, _qualification: u64) {}
 }
@@ -289,6 +359,8 @@ pub(crate) unsafe fn register<'a>(h: &'a mut dyn Hooks) {
 pub(crate) unsafe fn clear() {
     unsafe {
         hooks().clear();
+        rdmsr_intercepts().clear();
+        *cpuid_table() = None;
     }
 }
 
@@ -343,6 +415,12 @@ unsafe extern "C-unwind" fn bx_instr_hlt(cpu: u32) {
                 }
             }
         }
+
+        // this fires before bochs puts the cpu to sleep, so the run ends
+        // with the cpu halted rather than waiting for an interrupt
+        if stop_on_hlt(cpu) {
+            stop_for(cpu, RunExit::Hlt);
+        }
     }
 }
 
@@ -490,6 +568,8 @@ unsafe extern "C-unwind" fn bx_instr_opcode(
 #[unsafe(no_mangle)]
 unsafe extern "C-unwind" fn bx_instr_interrupt(cpu: u32, vector: u32) {
     unsafe {
+        step_interrupt(cpu, vector);
+
         hooks().iter_mut().for_each(|x| x.interrupt(cpu, vector));
 
         if let Some(e) = hook_event(cpu).take() {
@@ -506,6 +586,9 @@ unsafe extern "C-unwind" fn bx_instr_interrupt(cpu: u32, vector: u32) {
 #[unsafe(no_mangle)]
 unsafe extern "C-unwind" fn bx_instr_exception(cpu: u32, vector: u32, error_code: u32) {
     unsafe {
+        record_exception(cpu, vector);
+        step_exception(cpu, vector, error_code);
+
         hooks()
             .iter_mut()
             .for_each(|x| x.exception(cpu, vector, error_code));
@@ -551,6 +634,9 @@ extern "C-unwind" fn bx_instr_tlb_cntrl(cpu: u32, what: u32, new_cr3: PhyAddress
     };
 
     unsafe {
+        // whatever the guest did, host side translations may now be stale
+        cpu_memory(cpu).flush_translation_cache();
+
         hooks()
             .iter_mut()
             .for_each(|x| x.tlb_cntrl(cpu, ty, maybe_cr3));
@@ -632,6 +718,9 @@ unsafe extern "C-unwind" fn bx_instr_clflush(cpu: u32, laddr: Address, paddr: Ph
 #[unsafe(no_mangle)]
 unsafe extern "C-unwind" fn bx_instr_before_execution(cpu: u32, i: *mut c_void) {
     unsafe {
+        clear_exceptions(cpu);
+        check_run_limits(cpu);
+
         hooks().iter_mut().for_each(|x| x.before_execution(cpu, i));
 
         if let Some(e) = hook_event(cpu).take() {
@@ -642,6 +731,108 @@ unsafe extern "C-unwind" fn bx_instr_before_execution(cpu: u32, i: *mut c_void)
                 }
             }
         }
+
+        if !rdmsr_intercepts().is_empty() && instr_is_rdmsr(i) {
+            rdmsr(cpu, i);
+        }
+
+        // in a VMX or SVM guest CPUID exits to the hypervisor, which only the
+        // bochs handler does
+        if cpuid_table().is_some() && instr_is_cpuid(i) && !cpu_in_guest(cpu) {
+            cpuid(cpu, i);
+        }
+    }
+}
+
+// CPUID is completed here in place of the bochs handler, as there is no way
+// to change its result after the fact
+unsafe fn cpuid(cpu: u32, i: *mut c_void) {
+    unsafe {
+        let c = Cpu::from(cpu);
+        let leaf = c.rax() as u32;
+        let subleaf = c.rcx() as u32;
+
+        // bochs would have reported this from inside the handler
+        hooks().iter_mut().for_each(|x| x.cpuid(cpu));
+
+        let mut r = match cpuid_table().as_ref().and_then(|t| t.get(leaf, subleaf)) {
+            Some(r) => r,
+            None => c.cpuid(leaf, subleaf),
+        };
+
+        hooks()
+            .iter_mut()
+            .for_each(|x| x.cpuid_result(cpu, leaf, subleaf, &mut r));
+
+        if let Some(e) = hook_event(cpu).take() {
+            match e {
+                HookEvent::Stop | HookEvent::SetPc => cpu_bail(cpu),
+                HookEvent::Exception(vector, error) => {
+                    cpu_exception(cpu, vector, error.unwrap_or(0))
+                }
+            }
+        }
+
+        c.set_rax(r.eax as u64);
+        c.set_rbx(r.ebx as u64);
+        c.set_rcx(r.ecx as u64);
+        c.set_rdx(r.edx as u64);
+
+        complete_instruction(cpu, i)
+    }
+}
+
+// finish an instruction which was emulated here instead of by bochs. Bailing
+// out lands where bochs counts the instruction, but skips the after
+// execution instrumentation, so that is called first.
+unsafe fn complete_instruction(cpu: u32, i: *mut c_void) -> ! {
+    unsafe {
+        let c = Cpu::from(cpu);
+
+        c.set_rip(c.rip() + instr_ilen(i) as u64);
+        set_hook_event(cpu, None);
+
+        bx_instr_after_execution(cpu, i);
+
+        cpu_bail(cpu)
+    }
+}
+
+// bochs has no instrumentation point inside RDMSR, so intercepted reads are
+// caught before the instruction executes and completed here
+unsafe fn rdmsr(cpu: u32, i: *mut c_void) {
+    unsafe {
+        let c = Cpu::from(cpu);
+        let msr = c.rcx() as u32;
+
+        // outside of ring 0 the instruction raises #GP on its own
+        if c.cpl() != 0 || !rdmsr_intercepts().contains(&msr) {
+            return;
+        }
+
+        *msr_override(cpu) = None;
+
+        hooks().iter_mut().for_each(|x| x.rdmsr(cpu, msr));
+
+        if let Some(e) = hook_event(cpu).take() {
+            match e {
+                HookEvent::Stop | HookEvent::SetPc => cpu_bail(cpu),
+                HookEvent::Exception(vector, error) => {
+                    cpu_exception(cpu, vector, error.unwrap_or(0))
+                }
+            }
+        }
+
+        let Some(o) = msr_override(cpu).take() else {
+            return;
+        };
+
+        if let MsrOverride::Value(v) = o {
+            c.set_rax(v & 0xffff_ffff);
+            c.set_rdx(v >> 32);
+        }
+
+        complete_instruction(cpu, i)
     }
 }
 
@@ -664,6 +855,8 @@ unsafe extern "C-unwind" fn bx_instr_after_execution(cpu: u32, i: *mut c_void) {
 #[unsafe(no_mangle)]
 unsafe extern "C-unwind" fn bx_instr_repeat_iteration(cpu: u32, i: *mut c_void) {
     unsafe {
+        step_repeat_iteration(cpu);
+
         hooks().iter_mut().for_each(|x| x.repeat_iteration(cpu, i));
 
         if let Some(e) = hook_event(cpu).take() {
@@ -761,6 +954,8 @@ unsafe extern "C-unwind" fn bx_instr_cpuid(cpu: u32) {
 #[unsafe(no_mangle)]
 unsafe extern "C-unwind" fn bx_instr_wrmsr(cpu: u32, addr: u32, value: u64) {
     unsafe {
+        *msr_override(cpu) = None;
+
         hooks().iter_mut().for_each(|x| x.wrmsr(cpu, addr, value));
 
         if let Some(e) = hook_event(cpu).take() {
@@ -771,6 +966,21 @@ unsafe extern "C-unwind" fn bx_instr_wrmsr(cpu: u32, addr: u32, value: u64) {
                 }
             }
         }
+
+        // this is called from inside WRMSR after RIP has already moved past
+        // it, so bailing out before the write skips the rest of the
+        // instruction
+        match msr_override(cpu).take() {
+            None => (),
+            Some(MsrOverride::Skip) => cpu_bail(cpu),
+            Some(MsrOverride::Value(v)) => {
+                if Cpu::from(cpu).wrmsr(addr, v).is_err() {
+                    cpu_exception(cpu, 13, 0)
+                }
+
+                cpu_bail(cpu)
+            }
+        }
     }
 }
 
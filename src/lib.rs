@@ -8,12 +8,15 @@ pub static NUM_CPUS: usize = 255;
 pub type Address = u64;
 pub type PhyAddress = u64;
 
-mod logfunctions;
 mod params;
 mod sim;
 mod syncunsafecell;
 
+pub mod config;
 pub mod cpu;
+pub mod cpuid;
 pub mod hook;
+pub mod logfunctions;
 pub mod mem;
 pub mod opcode;
+pub mod snapshot;
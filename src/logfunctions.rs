@@ -1,66 +1,169 @@
 use std::ffi::CStr;
 use std::os::raw::c_char;
-use std::process;
 
-#[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_error(p: *const c_char) {
-    let s = unsafe {
-        assert!(!p.is_null());
+use crate::cpu::{EmulatorPanic, cpu_panic, running_cpu};
+use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref_mut};
 
-        CStr::from_ptr(p).to_str().unwrap()
-    };
+unsafe extern "C-unwind" {
+    fn logfunctions_get_mask() -> u32;
+    fn logfunctions_set_mask(mask: u32);
+}
 
-    error!("{}", s);
+/// The severity of a bochs log message
+///
+/// Panic and Fatal messages are always delivered, the others only once
+/// enabled with `enable_log_level`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum LogLevel {
+    Debug = 1 << 0,
+    Info = 1 << 1,
+    Warn = 1 << 2,
+    Error = 1 << 3,
+    Panic = 1 << 4,
+    Fatal = 1 << 5,
 }
 
-#[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_fatal1(p: *const c_char) {
-    logfunctions_error(p);
+type LogSink = Box<dyn FnMut(LogLevel, &str, &str)>;
 
-    process::exit(1);
+static LOG_SINK: SyncUnsafeCell<Option<LogSink>> = SyncUnsafeCell::new(None);
+
+unsafe fn log_sink() -> &'static mut Option<LogSink> {
+    unsafe { ptr_to_ref_mut(LOG_SINK.0.get()) }
 }
 
-#[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_info(p: *const c_char) {
-    let s = unsafe {
-        assert!(!p.is_null());
+/// Receive bochs log messages as (level, module prefix, message) instead of
+/// through the `log` crate
+///
+/// The `log` crate is built with `release_max_level_off`, so this is the
+/// only way to see bochs messages from release builds.
+///
+/// # Safety
+///
+/// bochs can call the sink at any point during a run, so this must not be
+/// called while a cpu is running on another thread.
+pub unsafe fn set_log_sink<T: FnMut(LogLevel, &str, &str) + 'static>(f: T) {
+    unsafe {
+        *log_sink() = Some(Box::new(f));
+    }
+}
 
-        CStr::from_ptr(p).to_str().unwrap()
-    };
+/// Go back to forwarding bochs log messages to the `log` crate
+///
+/// # Safety
+///
+/// Must not be called while a cpu is running on another thread, as the
+/// sink may be in use.
+pub unsafe fn clear_log_sink() {
+    unsafe {
+        *log_sink() = None;
+    }
+}
 
-    info!("{}", s);
+/// Choose whether bochs messages of `level` are formatted and delivered
+///
+/// Every level is enabled by default on debug builds and disabled on release
+/// builds. Disabled levels cost a single check in bochs.
+///
+/// # Safety
+///
+/// bochs reads the mask without any synchronization, so this must not race
+/// with a cpu running on another thread.
+pub unsafe fn enable_log_level(level: LogLevel, enabled: bool) {
+    unsafe {
+        let mask = logfunctions_get_mask();
+
+        if enabled {
+            logfunctions_set_mask(mask | level as u32);
+        } else {
+            logfunctions_set_mask(mask & !(level as u32));
+        }
+    }
 }
 
-#[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_ldebug(p: *const c_char) {
-    let s = unsafe {
+/// # Safety
+///
+/// Must not race with `enable_log_level`.
+pub unsafe fn log_level_enabled(level: LogLevel) -> bool {
+    match level {
+        LogLevel::Panic | LogLevel::Fatal => true,
+        _ => unsafe { logfunctions_get_mask() & level as u32 != 0 },
+    }
+}
+
+unsafe fn to_str<'a>(p: *const c_char) -> &'a str {
+    unsafe { CStr::from_ptr(p).to_str().unwrap_or("<invalid utf-8>") }
+}
+
+fn log(level: LogLevel, prefix: *const c_char, p: *const c_char) {
+    let (prefix, s) = unsafe {
         assert!(!p.is_null());
 
-        CStr::from_ptr(p).to_str().unwrap()
+        // the prefix is only set once the owner has called put()
+        let prefix = if prefix.is_null() { "" } else { to_str(prefix) };
+
+        (prefix, to_str(p))
     };
 
-    debug!("{}", s);
+    if let Some(f) = unsafe { log_sink() }.as_mut() {
+        return f(level, prefix, s);
+    }
+
+    let sep = if prefix.is_empty() { "" } else { " " };
+
+    match level {
+        LogLevel::Debug => debug!("{}{}{}", prefix, sep, s),
+        LogLevel::Info => info!("{}{}{}", prefix, sep, s),
+        LogLevel::Warn => warn!("{}{}{}", prefix, sep, s),
+        LogLevel::Error | LogLevel::Panic | LogLevel::Fatal => error!("{}{}{}", prefix, sep, s),
+    }
+}
+
+// bochs expects neither of these to return. A cpu which is running is
+// stopped and the panic handed back from `CpuRun::run`, anywhere else it
+// becomes a rust panic.
+fn bochs_panic(p: *const c_char, fatal: bool) -> ! {
+    let p = EmulatorPanic {
+        message: unsafe { to_str(p) }.to_string(),
+        fatal,
+    };
+
+    match running_cpu() {
+        Some(id) => unsafe { cpu_panic(id, p) },
+        None => panic!("{}", p),
+    }
 }
 
 #[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_lwarn(p: *const c_char) {
-    let s = unsafe {
-        assert!(!p.is_null());
+extern "C-unwind" fn logfunctions_error(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Error, prefix, p);
+}
 
-        CStr::from_ptr(p).to_str().unwrap()
-    };
+#[unsafe(no_mangle)]
+extern "C-unwind" fn logfunctions_fatal1(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Fatal, prefix, p);
 
-    warn!("{}", s);
+    bochs_panic(p, true)
 }
 
 #[unsafe(no_mangle)]
-extern "C-unwind" fn logfunctions_panic(p: *const c_char) {
-    let s = unsafe {
-        assert!(!p.is_null());
+extern "C-unwind" fn logfunctions_info(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Info, prefix, p);
+}
 
-        CStr::from_ptr(p).to_str().unwrap()
-    };
+#[unsafe(no_mangle)]
+extern "C-unwind" fn logfunctions_ldebug(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Debug, prefix, p);
+}
+
+#[unsafe(no_mangle)]
+extern "C-unwind" fn logfunctions_lwarn(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Warn, prefix, p);
+}
+
+#[unsafe(no_mangle)]
+extern "C-unwind" fn logfunctions_panic(prefix: *const c_char, p: *const c_char) {
+    log(LogLevel::Panic, prefix, p);
 
-    println!("{}", s);
-    process::exit(1);
+    bochs_panic(p, false)
 }
@@ -0,0 +1,311 @@
+use crate::hook::MemAccess;
+use crate::mem::{PagingMode, Translation};
+
+// #PF error code bits
+pub const PF_P: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_U: u32 = 1 << 2;
+pub const PF_RSVD: u32 = 1 << 3;
+pub const PF_I: u32 = 1 << 4;
+pub const PF_PK: u32 = 1 << 5;
+
+/// The cpu state which decides whether an access to a mapped page faults
+///
+/// `ac` is EFLAGS.AC, which lets supervisor code touch user pages with SMAP
+/// enabled. `pkru` is only consulted with `pke` set and 4 or 5-level
+/// paging, the only modes with protection keys.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct AccessContext {
+    pub cpl: u8,
+    pub wp: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub ac: bool,
+    pub nxe: bool,
+    pub pke: bool,
+    pub pkru: u32,
+}
+
+impl AccessContext {
+    pub fn from_regs(cpl: u8, cr0: u32, cr4: u32, efer: u32, rflags: u64) -> Self {
+        Self {
+            cpl,
+            wp: cr0 & (1 << 16) != 0,
+            smep: cr4 & (1 << 20) != 0,
+            smap: cr4 & (1 << 21) != 0,
+            ac: rflags & (1 << 18) != 0,
+            nxe: efer & (1 << 11) != 0,
+            pke: cr4 & (1 << 22) != 0,
+            pkru: 0,
+        }
+    }
+
+    // the error code bits which describe the access itself, P and the
+    // reason bits are added by whoever decides it faults
+    pub(crate) fn error_code(&self, mode: PagingMode, access: MemAccess) -> u32 {
+        let mut code = 0;
+
+        if matches!(access, MemAccess::Write | MemAccess::RW) {
+            code |= PF_W;
+        }
+
+        if self.cpl == 3 {
+            code |= PF_U;
+        }
+
+        let xd = self.nxe && !matches!(mode, PagingMode::Disabled | PagingMode::Legacy { .. });
+
+        if access == MemAccess::Execute && (self.smep || xd) {
+            code |= PF_I;
+        }
+
+        code
+    }
+}
+
+impl Translation {
+    /// The #PF error code an access through this translation would raise, or
+    /// `None` if it would succeed
+    pub fn fault(&self, mode: PagingMode, access: MemAccess, ctx: &AccessContext) -> Option<u32> {
+        let code = ctx.error_code(mode, access) | PF_P;
+
+        // XD is a reserved bit unless EFER.NXE is set
+        if self.nx && !ctx.nxe {
+            return Some(code | PF_RSVD);
+        }
+
+        let write = matches!(access, MemAccess::Write | MemAccess::RW);
+        let exec = access == MemAccess::Execute;
+
+        let denied = if ctx.cpl == 3 {
+            !self.user || (write && !self.writable) || (exec && self.nx)
+        } else if exec {
+            (self.user && ctx.smep) || self.nx
+        } else {
+            (self.user && ctx.smap && !ctx.ac) || (write && ctx.wp && !self.writable)
+        };
+
+        if denied {
+            return Some(code);
+        }
+
+        // protection keys only exist in IA-32e paging, and only apply to data
+        // accesses to user pages
+        let pk = ctx.pke && matches!(mode, PagingMode::Long4 | PagingMode::Long5);
+
+        if pk && self.user && !exec {
+            let pkru = ctx.pkru >> (self.pkey * 2);
+            let access_disable = pkru & 1 != 0;
+            let write_disable = pkru & 2 != 0;
+
+            if access_disable || (write && write_disable && (ctx.cpl == 3 || ctx.wp)) {
+                return Some(code | PF_PK);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: PagingMode = PagingMode::Long4;
+
+    fn page(writable: bool, user: bool, nx: bool) -> Translation {
+        Translation {
+            gpa: 0x1000,
+            page_size: 0x1000,
+            writable,
+            user,
+            nx,
+            pkey: 0,
+            global: false,
+            accessed: true,
+            dirty: true,
+        }
+    }
+
+    fn user() -> AccessContext {
+        AccessContext {
+            cpl: 3,
+            nxe: true,
+            ..Default::default()
+        }
+    }
+
+    fn kernel() -> AccessContext {
+        AccessContext {
+            nxe: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn user_mode() {
+        let ctx = user();
+
+        assert_eq!(
+            page(true, true, false).fault(LONG, MemAccess::RW, &ctx),
+            None
+        );
+        assert_eq!(
+            page(true, false, false).fault(LONG, MemAccess::Read, &ctx),
+            Some(PF_P | PF_U)
+        );
+        assert_eq!(
+            page(false, true, false).fault(LONG, MemAccess::Write, &ctx),
+            Some(PF_P | PF_W | PF_U)
+        );
+        assert_eq!(
+            page(true, true, true).fault(LONG, MemAccess::Execute, &ctx),
+            Some(PF_P | PF_U | PF_I)
+        );
+    }
+
+    #[test]
+    fn wp() {
+        let ro = page(false, false, false);
+
+        assert_eq!(ro.fault(LONG, MemAccess::Write, &kernel()), None);
+
+        let ctx = AccessContext {
+            wp: true,
+            ..kernel()
+        };
+
+        assert_eq!(ro.fault(LONG, MemAccess::Write, &ctx), Some(PF_P | PF_W));
+    }
+
+    #[test]
+    fn smep() {
+        let u = page(true, true, false);
+
+        assert_eq!(u.fault(LONG, MemAccess::Execute, &kernel()), None);
+
+        let ctx = AccessContext {
+            smep: true,
+            nxe: false,
+            ..kernel()
+        };
+
+        // the I bit is set by SMEP even without NXE
+        assert_eq!(u.fault(LONG, MemAccess::Execute, &ctx), Some(PF_P | PF_I));
+        assert_eq!(
+            page(true, false, false).fault(LONG, MemAccess::Execute, &ctx),
+            None
+        );
+    }
+
+    #[test]
+    fn smap() {
+        let u = page(true, true, false);
+        let ctx = AccessContext {
+            smap: true,
+            ..kernel()
+        };
+
+        assert_eq!(u.fault(LONG, MemAccess::Read, &ctx), Some(PF_P));
+        assert_eq!(u.fault(LONG, MemAccess::Write, &ctx), Some(PF_P | PF_W));
+        assert_eq!(
+            u.fault(LONG, MemAccess::Read, &AccessContext { ac: true, ..ctx }),
+            None
+        );
+        // SMAP does not apply to instruction fetches
+        assert_eq!(u.fault(LONG, MemAccess::Execute, &ctx), None);
+    }
+
+    #[test]
+    fn rsvd() {
+        let ctx = AccessContext {
+            nxe: false,
+            ..user()
+        };
+
+        assert_eq!(
+            page(true, true, true).fault(LONG, MemAccess::Read, &ctx),
+            Some(PF_P | PF_U | PF_RSVD)
+        );
+        // without NXE there is no XD, so fetches do not set I
+        assert_eq!(
+            page(true, true, true).fault(LONG, MemAccess::Execute, &ctx),
+            Some(PF_P | PF_U | PF_RSVD)
+        );
+    }
+
+    #[test]
+    fn legacy_has_no_xd() {
+        // NXE is set, but 32-bit paging has no XD bit so fetches do not set I
+        let ctx = user();
+
+        assert_eq!(
+            page(true, false, false).fault(
+                PagingMode::Legacy { pse: false },
+                MemAccess::Execute,
+                &ctx
+            ),
+            Some(PF_P | PF_U)
+        );
+    }
+
+    #[test]
+    fn pk() {
+        let mut t = page(true, true, false);
+        t.pkey = 1;
+
+        // key 1 write disable
+        let ctx = AccessContext {
+            pke: true,
+            pkru: 2 << 2,
+            ..user()
+        };
+
+        assert_eq!(t.fault(LONG, MemAccess::Read, &ctx), None);
+        assert_eq!(
+            t.fault(LONG, MemAccess::Write, &ctx),
+            Some(PF_P | PF_W | PF_U | PF_PK)
+        );
+        // keys do not apply to fetches
+        assert_eq!(t.fault(LONG, MemAccess::Execute, &ctx), None);
+
+        // supervisor writes only honor WD with CR0.WP
+        let ctx = AccessContext { cpl: 0, ..ctx };
+        assert_eq!(t.fault(LONG, MemAccess::Write, &ctx), None);
+        assert_eq!(
+            t.fault(LONG, MemAccess::Write, &AccessContext { wp: true, ..ctx }),
+            Some(PF_P | PF_W | PF_PK)
+        );
+
+        // key 1 access disable
+        let ctx = AccessContext {
+            pkru: 1 << 2,
+            ..user()
+        };
+        assert_eq!(t.fault(LONG, MemAccess::Read, &ctx), None);
+        assert_eq!(
+            t.fault(LONG, MemAccess::Read, &AccessContext { pke: true, ..ctx }),
+            Some(PF_P | PF_U | PF_PK)
+        );
+    }
+
+    #[test]
+    fn pae_has_no_pk() {
+        let mut t = page(true, true, false);
+        t.pkey = 1;
+
+        // CR4.PKE is ignored outside of IA-32e paging
+        let ctx = AccessContext {
+            pke: true,
+            pkru: 3 << 2,
+            ..user()
+        };
+
+        assert_eq!(t.fault(PagingMode::Pae, MemAccess::Read, &ctx), None);
+        assert_eq!(t.fault(PagingMode::Pae, MemAccess::Write, &ctx), None);
+        assert_eq!(
+            t.fault(PagingMode::Long5, MemAccess::Read, &ctx),
+            Some(PF_P | PF_U | PF_PK)
+        );
+    }
+}
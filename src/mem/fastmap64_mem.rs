@@ -1,47 +1,55 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::BuildHasherDefault;
 
 use fnv::FnvHasher;
 
 use crate::PhyAddress;
 use crate::mem::page_off;
-use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref_mut};
 
 pub type FastMap64<K, V> = HashMap<K, V, BuildHasherDefault<FnvHasher>>;
+pub type FastSet64<K> = HashSet<K, BuildHasherDefault<FnvHasher>>;
+
+/// Mapping of guest physical pages to host virtual pages
+#[derive(Default)]
+pub struct PageMap(FastMap64<PhyAddress, *mut u8>);
+
+impl PageMap {
+    pub unsafe fn resolve_hva(&self, gpa: PhyAddress) -> *mut u8 {
+        unsafe {
+            let (page, off) = page_off(gpa);
+            (*(self.0.get(&page).unwrap())).add(off)
+        }
+    }
 
-#[ctor]
-pub static MEM: SyncUnsafeCell<FastMap64<PhyAddress, *mut u8>> =
-    unsafe { SyncUnsafeCell::new(FastMap64::default()) };
+    pub unsafe fn resolve_hva_checked(&self, gpa: PhyAddress) -> Option<*mut u8> {
+        unsafe {
+            let (page, off) = page_off(gpa);
 
-unsafe fn mem() -> &'static mut FastMap64<PhyAddress, *mut u8> {
-    unsafe { ptr_to_ref_mut(MEM.0.get()) }
-}
+            self.0.get(&page).map(|p| p.add(off))
+        }
+    }
+
+    pub fn get(&self, gpa: PhyAddress) -> Option<*mut u8> {
+        let (page, _) = page_off(gpa);
 
-pub unsafe fn resolve_hva(gpa: PhyAddress) -> *mut u8 {
-    unsafe {
-        let (page, off) = page_off(gpa);
-        (*(mem().get(&page).unwrap())).add(off)
+        self.0.get(&page).copied()
     }
-}
 
-pub unsafe fn resolve_hva_checked(gpa: PhyAddress) -> Option<*mut u8> {
-    unsafe {
-        let (page, off) = page_off(gpa);
+    pub fn iter(&self) -> impl Iterator<Item = (PhyAddress, *mut u8)> + '_ {
+        self.0.iter().map(|(&gpa, &hva)| (gpa, hva))
+    }
 
-        mem().get(&page).map(|p| p.add(off))
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
-}
 
-pub unsafe fn page_insert(gpa: PhyAddress, hva: *mut u8) {
-    unsafe {
+    pub unsafe fn insert(&mut self, gpa: PhyAddress, hva: *mut u8) {
         let (page, _) = page_off(gpa);
-        mem().insert(page, hva);
+        self.0.insert(page, hva);
     }
-}
 
-pub unsafe fn page_remove(gpa: PhyAddress) {
-    unsafe {
+    pub unsafe fn remove(&mut self, gpa: PhyAddress) {
         let (page, _) = page_off(gpa);
-        mem().remove(&page);
+        self.0.remove(&page);
     }
 }
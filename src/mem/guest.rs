@@ -0,0 +1,347 @@
+use std::mem;
+use std::ops::Range;
+
+use crate::cpu::{RunExit, cpu_bail, cpu_exception, cpu_killbit, stop_for};
+use crate::mem::fastmap64_mem::{FastSet64, PageMap};
+use crate::mem::mmio::{MmioHandler, MmioRegion};
+use crate::mem::tlb::TranslationCache;
+use crate::mem::{PhyMemError, page_off, phy_mask};
+use crate::{Address, PhyAddress};
+
+/// What the missing page handler wants done about an unmapped guest page
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MissingPage {
+    /// Map the page at this host address and retry the access
+    Map(*mut u8),
+    /// Deliver an exception (vector, error code) to the guest instead of
+    /// performing the access
+    Exception(u32, Option<u16>),
+    /// Stop the cpu
+    Stop,
+}
+
+/// A guest physical address space
+///
+/// Each `GuestMemory` owns its own guest physical to host virtual page
+/// mapping and missing page handler. Cpus are bound to an instance with
+/// `Cpu::set_memory`, and any cpu which has not been bound uses the process
+/// wide default instance that backs the free functions in this module.
+pub struct GuestMemory {
+    pages: PageMap,
+    fault: Box<dyn FnMut(PhyAddress) -> MissingPage>,
+    dirty: Option<FastSet64<PhyAddress>>,
+    mmio: Vec<MmioRegion>,
+    pub(crate) tlb: Option<TranslationCache>,
+}
+
+impl Default for GuestMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestMemory {
+    pub fn new() -> Self {
+        Self {
+            pages: PageMap::default(),
+            fault: Box::new(|_| MissingPage::Stop),
+            dirty: None,
+            mmio: Vec::new(),
+            tlb: None,
+        }
+    }
+
+    // changes to the mapping itself are tracked as dirty as well, so that a
+    // snapshot restore knows which pages appeared or vanished since it was
+    // taken
+    /// # Safety
+    ///
+    /// `hva` must point to a page aligned, 0x1000 byte host allocation which
+    /// stays valid for as long as it is mapped, and no cpu may be running
+    /// against this memory.
+    pub unsafe fn page_insert(&mut self, gpa: PhyAddress, hva: *mut u8) {
+        unsafe {
+            assert_eq!(hva.align_offset(0x1000), 0);
+
+            self.pages.insert(gpa, hva);
+            self.mark_dirty(gpa);
+            self.flush_translation_cache();
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No cpu may be running against this memory. Host pointers previously
+    /// handed out for the page must no longer be used.
+    pub unsafe fn page_remove(&mut self, gpa: PhyAddress) {
+        unsafe {
+            self.pages.remove(gpa);
+            self.mark_dirty(gpa);
+            self.flush_translation_cache();
+        }
+    }
+
+    /// Look up the host page backing `gpa` without invoking the missing page
+    /// handler
+    pub fn page_hva(&self, gpa: PhyAddress) -> Option<*mut u8> {
+        self.pages.get(phy_mask(gpa))
+    }
+
+    /// All mapped pages as (guest physical, host virtual) page addresses
+    pub fn pages(&self) -> impl Iterator<Item = (PhyAddress, *mut u8)> + '_ {
+        self.pages.iter()
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Set the handler called when an unmapped guest physical page is
+    /// accessed
+    ///
+    /// Without a handler every missing page resolves to `MissingPage::Stop`.
+    /// Host side accesses have no cpu to stop or deliver an exception to, so
+    /// anything other than `MissingPage::Map` fails them with
+    /// `PhyMemError::PageNotPresent`.
+    pub fn missing_page<T: FnMut(PhyAddress) -> MissingPage + 'static>(&mut self, f: T) {
+        self.fault = Box::new(f);
+    }
+
+    /// Run the missing page handler for `gpa`, mapping the page if it asked
+    ///
+    /// # Safety
+    ///
+    /// The same as `page_insert` for any page the handler maps. This must not
+    /// be called from inside the missing page handler.
+    pub unsafe fn fault(&mut self, gpa: PhyAddress) -> MissingPage {
+        unsafe {
+            // the handler is swapped out while it runs so that it is free to map
+            // pages into this instance without aliasing itself
+            let mut f = mem::replace(&mut self.fault, Box::new(|_| MissingPage::Stop));
+            let r = f(gpa);
+            self.fault = f;
+
+            if let MissingPage::Map(hva) = r {
+                self.page_insert(gpa, hva);
+            }
+
+            r
+        }
+    }
+
+    pub(crate) unsafe fn guest_translate(&mut self, cpu: u32, gpa: PhyAddress) -> *mut u8 {
+        unsafe {
+            // i think this is needed because bochs will call into this with high bits
+            // set?
+            let real_gpa = phy_mask(gpa);
+
+            if let Some(hva) = self.pages.resolve_hva_checked(real_gpa) {
+                return hva;
+            }
+
+            match self.fault(real_gpa) {
+                MissingPage::Map(_) => (),
+                MissingPage::Exception(vector, error) => {
+                    cpu_exception(cpu, vector, error.unwrap_or(0))
+                }
+                MissingPage::Stop => stop_for(
+                    cpu,
+                    RunExit::MissingPage {
+                        gpa: page_off(real_gpa).0,
+                    },
+                ),
+            }
+
+            // check to see if our fault handler requested the cpu be killed
+            if cpu_killbit(cpu) != 0 {
+                cpu_bail(cpu)
+            }
+
+            self.pages.resolve_hva(real_gpa)
+        }
+    }
+
+    /// Translate a guest physical address to a host virtual address
+    ///
+    /// This is the host side counterpart of the translation bochs performs,
+    /// and as there is no associated cpu the fault handler cannot kill it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page at `gpa` is not mapped and the missing page handler
+    /// does not map it. Use `translate_checked` to get an error instead.
+    ///
+    /// # Safety
+    ///
+    /// The returned pointer is only valid while the page stays mapped, and
+    /// must not be used while a cpu is writing to the page.
+    pub unsafe fn translate(&mut self, gpa: PhyAddress) -> *mut u8 {
+        unsafe { self.translate_checked(gpa).unwrap() }
+    }
+
+    /// # Safety
+    ///
+    /// The same as `translate`.
+    pub unsafe fn translate_checked(&mut self, gpa: PhyAddress) -> Result<*mut u8, PhyMemError> {
+        unsafe {
+            let real_gpa = phy_mask(gpa);
+
+            if let Some(hva) = self.pages.resolve_hva_checked(real_gpa) {
+                return Ok(hva);
+            }
+
+            match self.fault(real_gpa) {
+                MissingPage::Map(_) => (),
+                _ => return Err(PhyMemError::PageNotPresent(page_off(real_gpa).0)),
+            }
+
+            self.pages
+                .resolve_hva_checked(real_gpa)
+                .ok_or(PhyMemError::PageNotPresent(page_off(real_gpa).0))
+        }
+    }
+
+    /// Start recording which guest physical pages are written
+    ///
+    /// Pages are marked when bochs writes them, when bochs hands out a host
+    /// pointer for writing, and when the host writes them with `phy_write` or
+    /// `virt_write`.
+    pub fn enable_dirty_tracking(&mut self) {
+        if self.dirty.is_none() {
+            self.dirty = Some(FastSet64::default());
+        }
+    }
+
+    pub fn disable_dirty_tracking(&mut self) {
+        self.dirty = None;
+    }
+
+    pub fn dirty_tracking(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// Page aligned guest physical addresses written since tracking was
+    /// enabled or last cleared
+    pub fn dirty_pages(&self) -> impl Iterator<Item = PhyAddress> + '_ {
+        self.dirty.iter().flatten().copied()
+    }
+
+    pub fn is_dirty(&self, gpa: PhyAddress) -> bool {
+        let (page, _) = page_off(phy_mask(gpa));
+
+        self.dirty.as_ref().is_some_and(|d| d.contains(&page))
+    }
+
+    /// Forget all dirty pages
+    ///
+    /// bochs caches the host pointers of writable pages in its TLB, and writes
+    /// through those cached pointers are not seen by the memory layer. The
+    /// TLB of every cpu using this memory must be flushed after clearing
+    /// (e.g. with `Cpu::set_mode` or `Cpu::set_state`) or later writes to
+    /// already dirty pages will be missed.
+    pub fn clear_dirty(&mut self) {
+        if let Some(d) = self.dirty.as_mut() {
+            d.clear();
+        }
+    }
+
+    pub(crate) fn mark_dirty(&mut self, gpa: PhyAddress) {
+        if let Some(d) = self.dirty.as_mut() {
+            let (page, _) = page_off(phy_mask(gpa));
+            d.insert(page);
+        }
+    }
+
+    /// Cache page table walks done by the host side `virt_*` functions
+    ///
+    /// The cache is dropped when any cpu bound to this memory changes its
+    /// TLB (`Hooks::tlb_cntrl`), when pages are mapped or unmapped, and when
+    /// the host writes a page table page. Guest writes to page tables are not
+    /// seen until the guest itself flushes its TLB, as on real hardware.
+    pub fn enable_translation_cache(&mut self) {
+        if self.tlb.is_none() {
+            self.tlb = Some(TranslationCache::default());
+        }
+    }
+
+    pub fn disable_translation_cache(&mut self) {
+        self.tlb = None;
+    }
+
+    pub fn flush_translation_cache(&mut self) {
+        if let Some(tlb) = self.tlb.as_mut() {
+            tlb.flush();
+        }
+    }
+
+    /// Drop cached translations of the page containing `gva` for every cr3
+    pub fn invalidate_translation(&mut self, gva: Address) {
+        if let Some(tlb) = self.tlb.as_mut() {
+            tlb.invalidate(gva);
+        }
+    }
+
+    /// Dispatch accesses to a guest physical range to `handler`
+    ///
+    /// Pages overlapping the range are never handed to bochs as host
+    /// pointers, so every guest access to them is routed through the handler.
+    /// Host side `phy_read`/`phy_write` on the range are dispatched as well.
+    /// Panics if the range overlaps an existing region.
+    pub fn register_mmio<T: MmioHandler + 'static>(
+        &mut self,
+        range: Range<PhyAddress>,
+        handler: T,
+    ) {
+        assert!(range.start < range.end);
+        assert!(
+            !self
+                .mmio
+                .iter()
+                .any(|r| r.range.start < range.end && range.start < r.range.end),
+            "mmio region {:x?} overlaps an existing region",
+            range
+        );
+
+        self.mmio.push(MmioRegion {
+            range,
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Remove the mmio region containing `gpa`, returning whether one existed
+    ///
+    /// bochs may still have the device pages cached in its TLB as handler
+    /// backed, so cpus using this memory should have their TLB flushed.
+    pub fn unregister_mmio(&mut self, gpa: PhyAddress) -> bool {
+        let len = self.mmio.len();
+        self.mmio.retain(|r| !r.contains(gpa));
+
+        self.mmio.len() != len
+    }
+
+    pub(crate) fn mmio_handler(
+        &mut self,
+        gpa: PhyAddress,
+    ) -> Option<&mut (dyn MmioHandler + 'static)> {
+        if self.mmio.is_empty() {
+            return None;
+        }
+
+        let gpa = phy_mask(gpa);
+
+        self.mmio
+            .iter_mut()
+            .find(|r| r.contains(gpa))
+            .map(|r| r.handler.as_mut())
+    }
+
+    pub(crate) fn is_mmio_page(&self, gpa: PhyAddress) -> bool {
+        if self.mmio.is_empty() {
+            return false;
+        }
+
+        let (page, _) = page_off(phy_mask(gpa));
+
+        self.mmio.iter().any(|r| r.overlaps_page(page))
+    }
+}
@@ -0,0 +1,31 @@
+use std::ops::Range;
+
+use crate::PhyAddress;
+
+/// Emulated device memory
+///
+/// Accesses to a guest physical range registered with
+/// `GuestMemory::register_mmio` are dispatched to these callbacks instead of
+/// RAM. `gpa` is the absolute guest physical address of the access, and an
+/// access never spans more than one page.
+pub trait MmioHandler {
+    fn read(&mut self, gpa: PhyAddress, buf: &mut [u8]);
+    fn write(&mut self, gpa: PhyAddress, data: &[u8]);
+}
+
+pub(crate) struct MmioRegion {
+    pub(crate) range: Range<PhyAddress>,
+    pub(crate) handler: Box<dyn MmioHandler>,
+}
+
+impl MmioRegion {
+    pub(crate) fn contains(&self, gpa: PhyAddress) -> bool {
+        self.range.contains(&gpa)
+    }
+
+    // bochs hands out host pointers a page at a time, so any page that
+    // overlaps a region has to go through the handlers
+    pub(crate) fn overlaps_page(&self, page: PhyAddress) -> bool {
+        self.range.start < page + 0x1000 && page < self.range.end
+    }
+}
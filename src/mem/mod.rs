@@ -1,54 +1,199 @@
+use std::iter;
+use std::ops::Range;
+use std::ptr;
 use std::slice;
 
-use crate::PhyAddress;
-use crate::cpu::{cpu_bail, cpu_killbit};
-use crate::syncunsafecell::SyncUnsafeCell;
+use crate::hook::MemAccess;
+use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref_mut};
+use crate::{NUM_CPUS, PhyAddress};
+
+mod access;
+pub use access::*;
+
+mod guest;
+pub use guest::{GuestMemory, MissingPage};
+
+mod mmio;
+pub use mmio::MmioHandler;
 
 mod phy;
 pub use phy::*;
 
+mod space;
+pub use space::*;
+
+mod tlb;
+
 mod virt;
 pub use virt::*;
 
 // despite all the benchmarks claiming that fxhash + hashbrown wins, for our
 // benchmarks fnvhash + hashbrown seems to be the winning combo
 mod fastmap64_mem;
-use fastmap64_mem::page_insert as mem_insert;
-pub use fastmap64_mem::page_remove;
-use fastmap64_mem::{resolve_hva, resolve_hva_checked};
+pub(crate) use fastmap64_mem::{FastMap64, FastSet64};
 
 pub const fn phy_mask(gpa: PhyAddress) -> PhyAddress {
     gpa & 0x000f_ffff_ffff_ffff
 }
 
 #[ctor]
-static FAULT: SyncUnsafeCell<Box<dyn FnMut(PhyAddress)>> =
-    unsafe { SyncUnsafeCell::new(Box::new(|_| panic!("no missing_page function set"))) };
+static MEM: SyncUnsafeCell<GuestMemory> = unsafe { SyncUnsafeCell::new(GuestMemory::new()) };
+
+// cpus which have not been bound to a GuestMemory have a null entry here and
+// use the default instance above
+#[ctor]
+static CPU_MEM: SyncUnsafeCell<Vec<*mut GuestMemory>> =
+    unsafe { SyncUnsafeCell::new(vec![ptr::null_mut(); NUM_CPUS]) };
 
 const fn page_off(a: PhyAddress) -> (PhyAddress, usize) {
     (a & !0xfff, a as usize & 0xfff)
 }
 
-pub unsafe fn fault(gpa: PhyAddress) {
+// split an access into pieces which do not cross a 4K page boundary
+pub(crate) fn chunked(start: u64, sz: usize) -> impl Iterator<Item = (u64, usize)> {
+    debug_assert!(start.checked_add(sz as u64).is_some());
+
+    let mut remaining = sz;
+    let mut base = start;
+
+    iter::from_fn(move || {
+        if remaining == 0 {
+            None
+        } else {
+            let chunk_base = base;
+
+            let chunk_sz = if base as usize + remaining > (base as usize & !0xfff) + 0x1000 {
+                ((base & !0xfff) + 0x1000 - base) as usize
+            } else {
+                remaining
+            };
+
+            base += chunk_sz as u64;
+            remaining -= chunk_sz;
+
+            Some((chunk_base, chunk_sz))
+        }
+    })
+}
+
+pub(crate) unsafe fn default_memory() -> &'static mut GuestMemory {
+    unsafe { ptr_to_ref_mut(MEM.0.get()) }
+}
+
+pub(crate) unsafe fn cpu_memory(id: u32) -> &'static mut GuestMemory {
     unsafe {
-        let f = FAULT.0.get();
-        (**f)(gpa);
+        let mem = ptr_to_ref_mut(CPU_MEM.0.get())[id as usize];
+
+        if mem.is_null() {
+            default_memory()
+        } else {
+            ptr_to_ref_mut(mem)
+        }
     }
 }
 
-pub unsafe fn page_insert(gpa: PhyAddress, hva: *mut u8) {
+pub(crate) unsafe fn bind_memory(id: u32, mem: *mut GuestMemory) {
     unsafe {
-        assert_eq!(hva.align_offset(0x1000), 0);
-
-        mem_insert(gpa, hva)
+        ptr_to_ref_mut(CPU_MEM.0.get())[id as usize] = mem;
     }
 }
 
+pub unsafe fn fault(gpa: PhyAddress) -> MissingPage {
+    unsafe { default_memory().fault(gpa) }
+}
+
+pub unsafe fn page_insert(gpa: PhyAddress, hva: *mut u8) {
+    unsafe { default_memory().page_insert(gpa, hva) }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, and host pointers
+/// previously handed out for the page, e.g. by `phy_translate`, must no longer
+/// be used.
+pub unsafe fn page_remove(gpa: PhyAddress) {
+    unsafe { default_memory().page_remove(gpa) }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, as bochs adds to the dirty
+/// set from its write path.
+pub unsafe fn enable_dirty_tracking() {
+    unsafe { default_memory().enable_dirty_tracking() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, as the dirty set is freed
+/// while bochs may be adding to it.
+pub unsafe fn disable_dirty_tracking() {
+    unsafe { default_memory().disable_dirty_tracking() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, as bochs adds to the dirty
+/// set while the guest writes.
+pub unsafe fn dirty_pages() -> Vec<PhyAddress> {
+    unsafe { default_memory().dirty_pages().collect() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory. Every cpu using it must
+/// have its TLB flushed afterwards, see `GuestMemory::clear_dirty`, or writes
+/// to pages which were already dirty are missed.
+pub unsafe fn clear_dirty_pages() {
+    unsafe { default_memory().clear_dirty() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, as its TLB changes flush
+/// the cache.
+pub unsafe fn enable_translation_cache() {
+    unsafe { default_memory().enable_translation_cache() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, as the cache is freed
+/// while a TLB change may be flushing it.
+pub unsafe fn disable_translation_cache() {
+    unsafe { default_memory().disable_translation_cache() }
+}
+
+/// # Safety
+///
+/// No cpu may be running against the default memory, and no host side `virt_*`
+/// call may be filling the cache at the same time.
+pub unsafe fn flush_translation_cache() {
+    unsafe { default_memory().flush_translation_cache() }
+}
+
 #[unsafe(no_mangle)]
-extern "C-unwind" fn mem_guest_to_host(cpu: u32, gpa: PhyAddress, _rw: u32) -> *mut u8 {
+extern "C-unwind" fn mem_guest_to_host(cpu: u32, gpa: PhyAddress, rw: u32) -> *mut u8 {
     trace!("translating guest phys {:x}...", gpa);
 
-    unsafe { guest_phy_translate(cpu, gpa) }
+    unsafe {
+        let m = cpu_memory(cpu);
+
+        // refusing direct access makes bochs fall back to readPhysicalPage
+        // and writePhysicalPage, which dispatch to the mmio handler
+        if m.is_mmio_page(gpa) {
+            return ptr::null_mut();
+        }
+
+        let hva = m.guest_translate(cpu, gpa);
+
+        // bochs will write through this pointer without telling us again
+        if rw == MemAccess::Write as u32 || rw == MemAccess::RW as u32 {
+            m.mark_dirty(gpa);
+        }
+
+        hva
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -58,10 +203,17 @@ extern "C-unwind" fn mem_read_phy(cpu: u32, gpa: PhyAddress, sz: u32, dst: *mut
     let sz = sz as usize;
 
     unsafe {
-        let src_ptr = guest_phy_translate(cpu, gpa);
-        let src = slice::from_raw_parts(src_ptr, sz);
+        let m = cpu_memory(cpu);
         let dst = slice::from_raw_parts_mut(dst, sz);
 
+        if let Some(h) = m.mmio_handler(gpa) {
+            h.read(phy_mask(gpa), dst);
+            return;
+        }
+
+        let src_ptr = m.guest_translate(cpu, gpa);
+        let src = slice::from_raw_parts(src_ptr, sz);
+
         dst.copy_from_slice(src);
         trace!("mem read {:x?}", src);
     }
@@ -74,57 +226,133 @@ extern "C-unwind" fn mem_write_phy(cpu: u32, gpa: PhyAddress, sz: u32, src: *con
     let sz = sz as usize;
 
     unsafe {
-        let dst_ptr = guest_phy_translate(cpu, gpa);
-        let dst = slice::from_raw_parts_mut(dst_ptr, sz);
+        let m = cpu_memory(cpu);
         let src = slice::from_raw_parts(src, sz);
 
+        if let Some(h) = m.mmio_handler(gpa) {
+            h.write(phy_mask(gpa), src);
+            return;
+        }
+
+        let dst_ptr = m.guest_translate(cpu, gpa);
+        m.mark_dirty(gpa);
+
+        let dst = slice::from_raw_parts_mut(dst_ptr, sz);
+
         dst.copy_from_slice(src);
         trace!("mem write {:x?}", src);
     }
 }
 
+/// Translate a guest physical address through the memory bound to `cpu`
 pub unsafe fn guest_phy_translate(cpu: u32, gpa: PhyAddress) -> *mut u8 {
-    unsafe {
-        // i think this is needed because bochs will call into this with high bits
-        // set?
-        let real_gpa = gpa & 0x000f_ffff_ffff_ffff;
-
-        if let Some(hva) = resolve_hva_checked(real_gpa) {
-            return hva;
-        }
-
-        fault(real_gpa);
-
-        // check to see if our fault handler requested the cpu be killed
-        if cpu_killbit(cpu) != 0 {
-            cpu_bail(cpu)
-        }
-
-        resolve_hva(real_gpa)
-    }
+    unsafe { cpu_memory(cpu).guest_translate(cpu, gpa) }
 }
 
 // this function exists to split translations happening by the emulator and
 // those requested by the guest. Emulator translations requests do not have an
 // associated cpu and thus cannot be killed by the page fault hook.
+/// # Panics
+///
+/// Panics if the page at `gpa` is not mapped and the missing page handler does
+/// not map it. Use `phy_translate_checked` to get an error instead.
+///
+/// # Safety
+///
+/// The returned pointer is only valid while the page stays mapped in the
+/// default `GuestMemory`, and must not be used while a cpu is writing to the
+/// page.
 pub unsafe fn phy_translate(gpa: PhyAddress) -> *mut u8 {
-    unsafe {
-        // i think this is needed because bochs will call into this with high bits
-        // set?
-        let real_gpa = phy_mask(gpa);
+    unsafe { default_memory().translate(gpa) }
+}
 
-        if let Some(hva) = resolve_hva_checked(real_gpa) {
-            return hva;
-        }
+/// # Safety
+///
+/// The same as `phy_translate`.
+pub unsafe fn phy_translate_checked(gpa: PhyAddress) -> Result<*mut u8, PhyMemError> {
+    unsafe { default_memory().translate_checked(gpa) }
+}
 
-        fault(real_gpa);
+pub unsafe fn missing_page<T: FnMut(PhyAddress) -> MissingPage + 'static>(f: T) {
+    unsafe { default_memory().missing_page(f) }
+}
 
-        resolve_hva(real_gpa)
-    }
+/// # Safety
+///
+/// No cpu may be running against the default memory. Pages in the range may
+/// already be cached in a cpu's TLB as ordinary memory, so every cpu using it
+/// should have its TLB flushed afterwards.
+pub unsafe fn register_mmio<T: MmioHandler + 'static>(range: Range<PhyAddress>, handler: T) {
+    unsafe { default_memory().register_mmio(range, handler) }
 }
 
-pub unsafe fn missing_page<T: FnMut(PhyAddress) + 'static>(f: T) {
-    unsafe {
-        *(FAULT.0.get()) = Box::new(f);
+/// # Safety
+///
+/// No cpu may be running against the default memory, as the handler is dropped
+/// while bochs may be calling it.
+pub unsafe fn unregister_mmio(gpa: PhyAddress) -> bool {
+    unsafe { default_memory().unregister_mmio(gpa) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(start: u64, sz: usize) -> Vec<(u64, usize)> {
+        chunked(start, sz).collect()
+    }
+
+    #[test]
+    fn chunked_within_page() {
+        assert_eq!(chunks(0x1000, 0), []);
+        assert_eq!(chunks(0x1000, 8), [(0x1000, 8)]);
+        assert_eq!(chunks(0x1ff8, 8), [(0x1ff8, 8)]);
+        assert_eq!(chunks(0x1000, 0x1000), [(0x1000, 0x1000)]);
+    }
+
+    #[test]
+    fn chunked_across_pages() {
+        assert_eq!(chunks(0x1ffc, 8), [(0x1ffc, 4), (0x2000, 4)]);
+        assert_eq!(
+            chunks(0x1800, 0x2000),
+            [(0x1800, 0x800), (0x2000, 0x1000), (0x3000, 0x800)]
+        );
+        assert_eq!(
+            chunks(0x1000, 0x2001),
+            [(0x1000, 0x1000), (0x2000, 0x1000), (0x3000, 1)]
+        );
+    }
+
+    #[repr(C, align(4096))]
+    struct Page([u8; 0x1000]);
+
+    #[test]
+    fn dirty_tracking() {
+        let mut pages = [const { Page([0; 0x1000]) }; 3];
+        let mut mem = GuestMemory::new();
+
+        for (i, p) in pages.iter_mut().enumerate() {
+            unsafe { mem.page_insert(i as PhyAddress * 0x1000, p.0.as_mut_ptr()) };
+        }
+
+        // nothing is recorded until tracking is enabled
+        mem.phy_write_checked(0, &[1]).unwrap();
+        assert_eq!(mem.dirty_pages().count(), 0);
+
+        mem.enable_dirty_tracking();
+        mem.phy_write_checked(0x1ffc, &[0xff; 8]).unwrap();
+
+        let mut dirty: Vec<_> = mem.dirty_pages().collect();
+        dirty.sort();
+        assert_eq!(dirty, [0x1000, 0x2000]);
+        assert!(mem.is_dirty(0x2fff));
+        assert!(!mem.is_dirty(0));
+
+        // reads do not dirty anything
+        mem.clear_dirty();
+        let mut buf = [0; 8];
+        mem.phy_read_slice_checked(0x1ffc, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 8]);
+        assert_eq!(mem.dirty_pages().count(), 0);
     }
 }
@@ -1,45 +1,212 @@
+use std::error::Error;
+use std::fmt;
 use std::mem;
 use std::slice;
 
 use crate::PhyAddress;
-use crate::mem::phy_translate;
+use crate::mem::{GuestMemory, chunked, default_memory, phy_mask};
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PhyMemError {
+    /// The page aligned guest physical address which was not mapped
+    PageNotPresent(PhyAddress),
+}
+
+impl fmt::Display for PhyMemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x?}", self)
+    }
+}
+
+impl Error for PhyMemError {
+    fn description(&self) -> &str {
+        "guest physical memory access error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+/// # Panics
+///
+/// Panics if a page holding the 8 bytes at `gpa` is not mapped and the missing
+/// page handler does not map it. Use `phy_read_u64_checked` to get an error
+/// instead.
 pub fn phy_read_u64(gpa: PhyAddress) -> u64 {
-    let mut buf = [0; mem::size_of::<u64>()];
-    phy_read_slice(gpa, &mut buf);
-    u64::from_le_bytes(buf)
+    unsafe { default_memory().phy_read_u64(gpa) }
 }
 
-pub fn phy_read_slice(gpa: PhyAddress, buf: &mut [u8]) {
-    // make sure we dont span pages
-    debug_assert!(gpa + (buf.len() as PhyAddress) <= (gpa & !0xfff) + 0x1000);
+pub fn phy_read_u64_checked(gpa: PhyAddress) -> Result<u64, PhyMemError> {
+    unsafe { default_memory().phy_read_u64_checked(gpa) }
+}
 
-    let src = unsafe {
-        let src_ptr = phy_translate(gpa);
-        slice::from_raw_parts(src_ptr, buf.len())
-    };
+/// # Panics
+///
+/// Panics if a page under `buf` is not mapped and the missing page handler does
+/// not map it. Use `phy_read_slice_checked` to get an error instead.
+pub fn phy_read_slice(gpa: PhyAddress, buf: &mut [u8]) {
+    unsafe { default_memory().phy_read_slice(gpa, buf) }
+}
 
-    buf.copy_from_slice(src);
+pub fn phy_read_slice_checked(gpa: PhyAddress, buf: &mut [u8]) -> Result<(), PhyMemError> {
+    unsafe { default_memory().phy_read_slice_checked(gpa, buf) }
 }
 
+/// # Panics
+///
+/// Panics if a page holding the `sz` bytes at `gpa` is not mapped and the
+/// missing page handler does not map it. Use `phy_read_checked` to get an
+/// error instead.
 pub fn phy_read(gpa: PhyAddress, buf: &mut Vec<u8>, sz: usize) {
-    // make sure we dont span pages
-    debug_assert!(gpa + (sz as PhyAddress) <= (gpa & !0xfff) + 0x1000);
+    unsafe { default_memory().phy_read(gpa, buf, sz) }
+}
 
-    let len = buf.len();
-    buf.reserve(sz);
-    let buf_slice = &mut buf[len..len + sz];
-    phy_read_slice(gpa, buf_slice)
+pub fn phy_read_checked(gpa: PhyAddress, buf: &mut Vec<u8>, sz: usize) -> Result<(), PhyMemError> {
+    unsafe { default_memory().phy_read_checked(gpa, buf, sz) }
 }
 
+/// # Panics
+///
+/// Panics if a page under `data` is not mapped and the missing page handler
+/// does not map it, after the pages before it have been written. Use
+/// `phy_write_checked` to get an error instead.
 pub fn phy_write(gpa: PhyAddress, data: &[u8]) {
-    // make sure we dont span pages
-    debug_assert!(gpa + (data.len() as PhyAddress) <= (gpa & !0xfff) + 0x1000);
+    unsafe { default_memory().phy_write(gpa, data) }
+}
+
+pub fn phy_write_checked(gpa: PhyAddress, data: &[u8]) -> Result<(), PhyMemError> {
+    unsafe { default_memory().phy_write_checked(gpa, data) }
+}
+
+impl GuestMemory {
+    /// # Panics
+    ///
+    /// Panics if a page holding the 8 bytes at `gpa` is not mapped and the
+    /// missing page handler does not map it. Use `phy_read_u64_checked` to get
+    /// an error instead.
+    pub fn phy_read_u64(&mut self, gpa: PhyAddress) -> u64 {
+        self.phy_read_u64_checked(gpa).unwrap()
+    }
+
+    pub fn phy_read_u64_checked(&mut self, gpa: PhyAddress) -> Result<u64, PhyMemError> {
+        let mut buf = [0; mem::size_of::<u64>()];
+        self.phy_read_slice_checked(gpa, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if a page under `buf` is not mapped and the missing page handler
+    /// does not map it. Use `phy_read_slice_checked` to get an error instead.
+    pub fn phy_read_slice(&mut self, gpa: PhyAddress, buf: &mut [u8]) {
+        self.phy_read_slice_checked(gpa, buf).unwrap()
+    }
+
+    pub fn phy_read_slice_checked(
+        &mut self,
+        gpa: PhyAddress,
+        buf: &mut [u8],
+    ) -> Result<(), PhyMemError> {
+        let mut off = 0;
+
+        for (start, sz) in chunked(gpa, buf.len()) {
+            self.phy_read_page(start, &mut buf[off..off + sz])?;
+            off += sz;
+        }
+
+        Ok(())
+    }
+
+    fn phy_read_page(&mut self, gpa: PhyAddress, buf: &mut [u8]) -> Result<(), PhyMemError> {
+        if let Some(h) = self.mmio_handler(gpa) {
+            h.read(phy_mask(gpa), buf);
+            return Ok(());
+        }
+
+        let src = unsafe {
+            let src_ptr = self.translate_checked(gpa)?;
+            slice::from_raw_parts(src_ptr, buf.len())
+        };
+
+        buf.copy_from_slice(src);
+
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if a page holding the `sz` bytes at `gpa` is not mapped and the
+    /// missing page handler does not map it. Use `phy_read_checked` to get an
+    /// error instead.
+    pub fn phy_read(&mut self, gpa: PhyAddress, buf: &mut Vec<u8>, sz: usize) {
+        self.phy_read_checked(gpa, buf, sz).unwrap()
+    }
+
+    pub fn phy_read_checked(
+        &mut self,
+        gpa: PhyAddress,
+        buf: &mut Vec<u8>,
+        sz: usize,
+    ) -> Result<(), PhyMemError> {
+        let len = buf.len();
+        buf.resize(len + sz, 0);
+
+        let r = self.phy_read_slice_checked(gpa, &mut buf[len..len + sz]);
+
+        // if we errored, roll the length back to the original
+        if r.is_err() {
+            buf.truncate(len);
+        }
+
+        r
+    }
+
+    /// # Panics
+    ///
+    /// Panics if a page under `data` is not mapped and the missing page handler
+    /// does not map it, after the pages before it have been written. Use
+    /// `phy_write_checked` to get an error instead.
+    pub fn phy_write(&mut self, gpa: PhyAddress, data: &[u8]) {
+        self.phy_write_checked(gpa, data).unwrap()
+    }
+
+    /// Write `data` at `gpa`, which may span any number of pages
+    ///
+    /// On error the pages before the missing one have already been written.
+    pub fn phy_write_checked(&mut self, gpa: PhyAddress, data: &[u8]) -> Result<(), PhyMemError> {
+        let mut off = 0;
+
+        for (start, sz) in chunked(gpa, data.len()) {
+            self.phy_write_page(start, &data[off..off + sz])?;
+            off += sz;
+        }
+
+        Ok(())
+    }
+
+    fn phy_write_page(&mut self, gpa: PhyAddress, data: &[u8]) -> Result<(), PhyMemError> {
+        if let Some(h) = self.mmio_handler(gpa) {
+            h.write(phy_mask(gpa), data);
+            return Ok(());
+        }
+
+        let dst = unsafe {
+            let dst_ptr = self.translate_checked(gpa)?;
+            slice::from_raw_parts_mut(dst_ptr, data.len())
+        };
+
+        dst.copy_from_slice(data);
+        self.mark_dirty(gpa);
 
-    let dst = unsafe {
-        let dst_ptr = phy_translate(gpa);
-        slice::from_raw_parts_mut(dst_ptr, data.len())
-    };
+        if self
+            .tlb
+            .as_ref()
+            .is_some_and(|tlb| tlb.is_table(phy_mask(gpa)))
+        {
+            self.flush_translation_cache();
+        }
 
-    dst.copy_from_slice(data);
+        Ok(())
+    }
 }
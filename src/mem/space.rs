@@ -0,0 +1,336 @@
+use std::alloc::{self, Layout};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+use crate::mem::{GuestMemory, PhyMemError, default_memory, phy_mask};
+use crate::{Address, PhyAddress};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+}
+
+impl PageSize {
+    pub const fn size(self) -> u64 {
+        match self {
+            PageSize::Page4K => 0x1000,
+            PageSize::Page2M => 0x20_0000,
+            PageSize::Page1G => 0x4000_0000,
+        }
+    }
+
+    // the table level holding the leaf entry, 0 being the PML4
+    const fn leaf_level(self) -> usize {
+        match self {
+            PageSize::Page4K => 3,
+            PageSize::Page2M => 2,
+            PageSize::Page1G => 1,
+        }
+    }
+}
+
+/// Rights of a mapping. Pages are always readable and present, and `exec`
+/// clear sets XD, which needs EFER.NXE
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Perms {
+    pub write: bool,
+    pub user: bool,
+    pub exec: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MapError {
+    /// The virtual address, physical address or length is not a multiple of
+    /// the page size
+    Misaligned,
+    NonCanonical,
+    /// The virtual address is already covered by another mapping
+    AlreadyMapped(Address),
+    PhyMem(PhyMemError),
+}
+
+impl From<PhyMemError> for MapError {
+    fn from(e: PhyMemError) -> Self {
+        MapError::PhyMem(e)
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x?}", self)
+    }
+}
+
+impl Error for MapError {
+    fn description(&self) -> &str {
+        "page table construction error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
+
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_US: u64 = 1 << 2;
+const PTE_PS: u64 = 1 << 7;
+const PTE_XD: u64 = 1 << 63;
+
+const fn table_index(gva: Address, level: usize) -> u64 {
+    (gva >> (39 - 9 * level)) & 0x1ff
+}
+
+const fn entry_addr(table: PhyAddress, gva: Address, level: usize) -> PhyAddress {
+    table + table_index(gva, level) * mem::size_of::<u64>() as u64
+}
+
+const fn canonical(gva: Address) -> bool {
+    ((gva << 16) as i64 >> 16) as Address == gva
+}
+
+/// Builder for 4-level page tables
+///
+/// Page table pages are handed out from a bump allocator starting at a guest
+/// physical address picked by the caller, and are backed by freshly
+/// allocated host pages inserted with `page_insert`, replacing anything
+/// already mapped there. Like any other host page given to a `GuestMemory`
+/// they are never freed. Only the page tables are created, the physical
+/// memory being mapped has to be provided separately.
+pub struct AddressSpace<'a> {
+    mem: &'a mut GuestMemory,
+    cr3: PhyAddress,
+    next_table: PhyAddress,
+}
+
+impl<'a> AddressSpace<'a> {
+    /// Start an empty address space whose tables are allocated upwards from
+    /// `table_base`
+    ///
+    /// # Safety
+    ///
+    /// The tables are written straight into `mem`, so no cpu may be running
+    /// against it while the address space is being built.
+    pub unsafe fn new(mem: &'a mut GuestMemory, table_base: PhyAddress) -> Self {
+        unsafe {
+            assert_eq!(table_base & 0xfff, 0);
+
+            let mut s = Self {
+                mem,
+                cr3: 0,
+                next_table: phy_mask(table_base),
+            };
+
+            s.cr3 = s.alloc_table();
+
+            s
+        }
+    }
+
+    /// The root of the tables, to be loaded into CR3
+    pub fn cr3(&self) -> PhyAddress {
+        self.cr3
+    }
+
+    /// The next guest physical address the table allocator will hand out
+    pub fn next_table(&self) -> PhyAddress {
+        self.next_table
+    }
+
+    unsafe fn alloc_table(&mut self) -> PhyAddress {
+        unsafe {
+            let layout = Layout::from_size_align(0x1000, 0x1000).unwrap();
+            let hva = alloc::alloc_zeroed(layout);
+            assert!(!hva.is_null());
+
+            let gpa = self.next_table;
+            self.next_table += 0x1000;
+
+            self.mem.page_insert(gpa, hva);
+
+            gpa
+        }
+    }
+
+    fn read_entry(&mut self, gpa: PhyAddress) -> Result<u64, PhyMemError> {
+        self.mem.phy_read_u64_checked(gpa)
+    }
+
+    fn write_entry(&mut self, gpa: PhyAddress, entry: u64) -> Result<(), PhyMemError> {
+        self.mem.phy_write_checked(gpa, &entry.to_le_bytes())
+    }
+
+    /// Map `len` bytes at `gva` to `gpa` using pages of `size`
+    ///
+    /// Intermediate entries are created with every right set, so the rights
+    /// of the leaf entries are the effective ones. The whole range is checked
+    /// before anything is written, so on error nothing has been mapped and
+    /// `AlreadyMapped` holds the first page in the way.
+    pub fn map(
+        &mut self,
+        gva: Address,
+        gpa: PhyAddress,
+        len: u64,
+        perms: Perms,
+        size: PageSize,
+    ) -> Result<(), MapError> {
+        let sz = size.size();
+
+        if (gva | gpa | len) & (sz - 1) != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let last = gva.checked_add(len - 1).ok_or(MapError::NonCanonical)?;
+
+        if !canonical(gva) || !canonical(last) || (gva as i64 ^ last as i64) < 0 {
+            return Err(MapError::NonCanonical);
+        }
+
+        for off in (0..len).step_by(sz as usize) {
+            self.check_page(gva + off, size)?;
+        }
+
+        for off in (0..len).step_by(sz as usize) {
+            self.map_page(gva + off, gpa + off, perms, size)?;
+        }
+
+        Ok(())
+    }
+
+    // walk the existing tables without creating any, failing if `map_page`
+    // would
+    fn check_page(&mut self, gva: Address, size: PageSize) -> Result<(), MapError> {
+        let mut table = self.cr3;
+
+        for level in 0..size.leaf_level() {
+            let entry = self.read_entry(entry_addr(table, gva, level))?;
+
+            if entry & PTE_P == 0 {
+                return Ok(());
+            } else if entry & PTE_PS != 0 {
+                return Err(MapError::AlreadyMapped(gva));
+            }
+
+            table = phy_mask(entry) & !0xfff;
+        }
+
+        if self.read_entry(entry_addr(table, gva, size.leaf_level()))? & PTE_P != 0 {
+            return Err(MapError::AlreadyMapped(gva));
+        }
+
+        Ok(())
+    }
+
+    fn map_page(
+        &mut self,
+        gva: Address,
+        gpa: PhyAddress,
+        perms: Perms,
+        size: PageSize,
+    ) -> Result<(), MapError> {
+        let mut table = self.cr3;
+
+        for level in 0..size.leaf_level() {
+            let entry_addr = entry_addr(table, gva, level);
+            let entry = self.read_entry(entry_addr)?;
+
+            if entry & PTE_P == 0 {
+                let next = unsafe { self.alloc_table() };
+                self.write_entry(entry_addr, next | PTE_P | PTE_RW | PTE_US)?;
+                table = next;
+            } else if entry & PTE_PS != 0 {
+                return Err(MapError::AlreadyMapped(gva));
+            } else {
+                table = phy_mask(entry) & !0xfff;
+            }
+        }
+
+        let entry_addr = entry_addr(table, gva, size.leaf_level());
+
+        if self.read_entry(entry_addr)? & PTE_P != 0 {
+            return Err(MapError::AlreadyMapped(gva));
+        }
+
+        let mut entry = phy_mask(gpa) | PTE_P;
+
+        if perms.write {
+            entry |= PTE_RW;
+        }
+
+        if perms.user {
+            entry |= PTE_US;
+        }
+
+        if !perms.exec {
+            entry |= PTE_XD;
+        }
+
+        if size != PageSize::Page4K {
+            entry |= PTE_PS;
+        }
+
+        self.write_entry(entry_addr, entry)?;
+
+        Ok(())
+    }
+}
+
+impl AddressSpace<'static> {
+    /// Build an address space in the default guest memory
+    ///
+    /// # Safety
+    ///
+    /// The same as `new`, and the default `GuestMemory` must not be borrowed
+    /// anywhere else while the address space is alive.
+    pub unsafe fn new_default(table_base: PhyAddress) -> Self {
+        unsafe { Self::new(default_memory(), table_base) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: Perms = Perms {
+        write: true,
+        user: false,
+        exec: true,
+    };
+
+    #[test]
+    fn overlap_maps_nothing() {
+        let mut mem = GuestMemory::new();
+        let mut space = unsafe { AddressSpace::new(&mut mem, 0x10_0000) };
+
+        space.map(0x2000, 0, 0x1000, RW, PageSize::Page4K).unwrap();
+
+        assert_eq!(
+            space.map(0, 0, 0x4000, RW, PageSize::Page4K),
+            Err(MapError::AlreadyMapped(0x2000))
+        );
+
+        // none of the pages around the conflict were written
+        space.map(0, 0, 0x2000, RW, PageSize::Page4K).unwrap();
+        space.map(0x3000, 0, 0x1000, RW, PageSize::Page4K).unwrap();
+
+        assert_eq!(
+            space.map(0x20_0000, 0, 0x20_0000, RW, PageSize::Page2M),
+            Ok(())
+        );
+        assert_eq!(
+            space.map(0, 0, 0x20_0000, RW, PageSize::Page2M),
+            Err(MapError::AlreadyMapped(0))
+        );
+        assert_eq!(
+            space.map(0x20_0000, 0, 0x1000, RW, PageSize::Page4K),
+            Err(MapError::AlreadyMapped(0x20_0000))
+        );
+    }
+}
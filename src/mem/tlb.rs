@@ -0,0 +1,77 @@
+use crate::mem::fastmap64_mem::{FastMap64, FastSet64};
+use crate::mem::{PagingMode, Translation, page_off};
+use crate::{Address, PhyAddress};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct Key {
+    mode: PagingMode,
+    cr3: PhyAddress,
+    page: Address,
+}
+
+/// Host side cache of page table walks, keyed by (paging mode, cr3, 4K
+/// virtual page)
+///
+/// Every page table page read while filling the cache is remembered, so that
+/// host writes to those pages can drop the cache.
+#[derive(Default)]
+pub(crate) struct TranslationCache {
+    entries: FastMap64<Key, Translation>,
+    tables: FastSet64<PhyAddress>,
+}
+
+impl TranslationCache {
+    pub(crate) fn get(
+        &self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+    ) -> Option<Translation> {
+        let (page, off) = page_off(gva);
+        let key = Key { mode, cr3, page };
+
+        self.entries.get(&key).map(|t| Translation {
+            gpa: t.gpa + off as PhyAddress,
+            ..*t
+        })
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+        t: Translation,
+    ) {
+        let (page, off) = page_off(gva);
+        let key = Key { mode, cr3, page };
+
+        self.entries.insert(
+            key,
+            Translation {
+                gpa: t.gpa - off as PhyAddress,
+                ..t
+            },
+        );
+    }
+
+    pub(crate) fn add_table(&mut self, gpa: PhyAddress) {
+        let (page, _) = page_off(gpa);
+        self.tables.insert(page);
+    }
+
+    pub(crate) fn is_table(&self, gpa: PhyAddress) -> bool {
+        let (page, _) = page_off(gpa);
+        self.tables.contains(&page)
+    }
+
+    pub(crate) fn invalidate(&mut self, gva: Address) {
+        let (page, _) = page_off(gva);
+        self.entries.retain(|k, _| k.page != page);
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.entries.clear();
+        self.tables.clear();
+    }
+}
@@ -1,115 +1,742 @@
 use std::error::Error;
 use std::fmt;
-use std::iter;
 use std::mem;
+use std::ops::RangeInclusive;
 
-use crate::mem::{phy_mask, phy_read_slice, phy_read_u64, phy_write};
+use crate::hook::MemAccess;
+use crate::mem::{
+    AccessContext, GuestMemory, PF_P, PhyMemError, chunked, default_memory, phy_mask,
+};
 use crate::{Address, PhyAddress};
 
-const fn pml4_index(gva: Address) -> u64 {
-    gva >> (12 + (9 * 3)) & 0x1ff
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum VirtMemError {
+    Pml5eNotPresent,
+    Pml4eNotPresent,
+    PdpteNotPresent,
+    PdeNotPresent,
+    PteNotPresent,
+    /// A page table or data page was not mapped in guest physical memory
+    PhyMem(PhyMemError),
+    /// The address does not fit in the linear address space of the paging
+    /// mode, e.g. above 4G with 32-bit or PAE paging
+    AddressOutOfRange,
+    /// The address is not canonical for the 48 or 57-bit linear address
+    /// width of the paging mode
+    NonCanonical,
 }
 
-const fn pdpt_index(gva: Address) -> u64 {
-    gva >> (12 + (9 * 2)) & 0x1ff
+impl From<PhyMemError> for VirtMemError {
+    fn from(e: PhyMemError) -> Self {
+        VirtMemError::PhyMem(e)
+    }
 }
 
-const fn pd_index(gva: Address) -> u64 {
-    gva >> (12 + (9 * 1)) & 0x1ff
+impl fmt::Display for VirtMemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
-const fn pt_index(gva: Address) -> u64 {
-    gva >> (12 + (9 * 0)) & 0x1ff
+impl Error for VirtMemError {
+    fn description(&self) -> &str {
+        "virtual to physical translation error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
 }
 
-const fn base_flags(gpa: Address) -> (Address, u64) {
-    (phy_mask(gpa) & !0xfff, gpa & 0x1ff)
+/// The page table format used to translate linear addresses
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PagingMode {
+    /// CR0.PG=0, linear addresses are physical addresses
+    Disabled,
+    /// 32-bit paging, 4M pages are only honored with CR4.PSE=1
+    Legacy { pse: bool },
+    /// CR4.PAE=1 outside of long mode, 3 levels with 4 PDPTEs
+    Pae,
+    /// IA-32e 4-level paging
+    Long4,
+    /// IA-32e 5-level paging, CR4.LA57=1
+    Long5,
 }
 
-const fn pte_flags(pte: Address) -> (PhyAddress, u64) {
-    (phy_mask(pte) & !0xfff, pte & 0xfff)
+impl PagingMode {
+    /// Derive the paging mode bochs would use from the control registers
+    pub fn from_regs(cr0: u32, cr4: u32, efer: u32) -> Self {
+        const CR0_PG: u32 = 1 << 31;
+        const CR4_PSE: u32 = 1 << 4;
+        const CR4_PAE: u32 = 1 << 5;
+        const CR4_LA57: u32 = 1 << 12;
+        const EFER_LMA: u32 = 1 << 10;
+
+        if cr0 & CR0_PG == 0 {
+            PagingMode::Disabled
+        } else if efer & EFER_LMA != 0 && cr4 & CR4_LA57 != 0 {
+            PagingMode::Long5
+        } else if efer & EFER_LMA != 0 {
+            PagingMode::Long4
+        } else if cr4 & CR4_PAE != 0 {
+            PagingMode::Pae
+        } else {
+            PagingMode::Legacy {
+                pse: cr4 & CR4_PSE != 0,
+            }
+        }
+    }
+
+    fn levels(self) -> &'static [Level] {
+        match self {
+            PagingMode::Disabled => &[],
+            PagingMode::Legacy { pse: false } => &LEGACY,
+            PagingMode::Legacy { pse: true } => &LEGACY_PSE,
+            PagingMode::Pae => &PAE,
+            PagingMode::Long4 => &LONG5[1..],
+            PagingMode::Long5 => &LONG5,
+        }
+    }
+
+    fn table_base(self, cr3: PhyAddress) -> PhyAddress {
+        match self {
+            PagingMode::Disabled => 0,
+            PagingMode::Legacy { .. } => cr3 & 0xffff_f000,
+            // the PDPT is only 32 byte aligned
+            PagingMode::Pae => cr3 & 0xffff_ffe0,
+            PagingMode::Long4 | PagingMode::Long5 => phy_mask(cr3) & !0xfff,
+        }
+    }
+
+    // sign extend an address built from table indices
+    fn canonical(self, gva: Address) -> Address {
+        let shift = match self {
+            PagingMode::Long4 => 64 - 48,
+            PagingMode::Long5 => 64 - 57,
+            _ => return gva,
+        };
+
+        ((gva << shift) as i64 >> shift) as Address
+    }
+
+    fn check_address(self, gva: Address) -> Result<(), VirtMemError> {
+        let bits = match self {
+            PagingMode::Disabled | PagingMode::Legacy { .. } | PagingMode::Pae => {
+                return if gva > 0xffff_ffff {
+                    Err(VirtMemError::AddressOutOfRange)
+                } else {
+                    Ok(())
+                };
+            }
+            PagingMode::Long4 => 48,
+            PagingMode::Long5 => 57,
+        };
+
+        // everything above the top implemented bit must be a copy of it
+        let shift = 64 - bits;
+
+        if ((gva << shift) as i64 >> shift) as Address != gva {
+            return Err(VirtMemError::NonCanonical);
+        }
+
+        Ok(())
+    }
 }
 
-const fn page_offset(gva: Address) -> u64 {
-    gva & 0xfff
+// one level of a page table walk. `shift` is the lowest linear address bit
+// translated by an entry at this level, which is also the log2 of the page
+// size if the entry maps a page
+struct Level {
+    shift: u32,
+    index_bits: u32,
+    entry_size: usize,
+    // whether bit 7 (PS) maps a page at this level
+    large: bool,
+    // whether the R/W, U/S and XD bits apply, PAE PDPTEs have none of them
+    rights: bool,
+    not_present: VirtMemError,
 }
 
+const LEGACY: [Level; 2] = [
+    Level {
+        shift: 22,
+        index_bits: 10,
+        entry_size: 4,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::PdeNotPresent,
+    },
+    Level {
+        shift: 12,
+        index_bits: 10,
+        entry_size: 4,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::PteNotPresent,
+    },
+];
+
+const LEGACY_PSE: [Level; 2] = [
+    Level {
+        shift: 22,
+        index_bits: 10,
+        entry_size: 4,
+        large: true,
+        rights: true,
+        not_present: VirtMemError::PdeNotPresent,
+    },
+    Level {
+        shift: 12,
+        index_bits: 10,
+        entry_size: 4,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::PteNotPresent,
+    },
+];
+
+const PAE: [Level; 3] = [
+    Level {
+        shift: 30,
+        index_bits: 2,
+        entry_size: 8,
+        large: false,
+        rights: false,
+        not_present: VirtMemError::PdpteNotPresent,
+    },
+    Level {
+        shift: 21,
+        index_bits: 9,
+        entry_size: 8,
+        large: true,
+        rights: true,
+        not_present: VirtMemError::PdeNotPresent,
+    },
+    Level {
+        shift: 12,
+        index_bits: 9,
+        entry_size: 8,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::PteNotPresent,
+    },
+];
+
+// 4-level paging is the same walk without the PML5
+const LONG5: [Level; 5] = [
+    Level {
+        shift: 48,
+        index_bits: 9,
+        entry_size: 8,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::Pml5eNotPresent,
+    },
+    Level {
+        shift: 39,
+        index_bits: 9,
+        entry_size: 8,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::Pml4eNotPresent,
+    },
+    Level {
+        shift: 30,
+        index_bits: 9,
+        entry_size: 8,
+        large: true,
+        rights: true,
+        not_present: VirtMemError::PdpteNotPresent,
+    },
+    Level {
+        shift: 21,
+        index_bits: 9,
+        entry_size: 8,
+        large: true,
+        rights: true,
+        not_present: VirtMemError::PdeNotPresent,
+    },
+    Level {
+        shift: 12,
+        index_bits: 9,
+        entry_size: 8,
+        large: false,
+        rights: true,
+        not_present: VirtMemError::PteNotPresent,
+    },
+];
+
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_US: u64 = 1 << 2;
+const PTE_A: u64 = 1 << 5;
+const PTE_D: u64 = 1 << 6;
+const PTE_PS: u64 = 1 << 7;
+const PTE_G: u64 = 1 << 8;
+const PTE_XD: u64 = 1 << 63;
+
+/// The result of a page table walk
+///
+/// The rights are the effective ones, combined across every level of the
+/// walk. `nx` is the raw XD bit and only means anything with EFER.NXE=1.
+/// `pkey` is only set by the IA-32e modes.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-pub enum VirtMemError {
-    Pml4eNotPresent,
-    PdpteNotPresent,
-    PdeNotPresent,
-    PteNotPresent,
+pub struct Translation {
+    pub gpa: PhyAddress,
+    pub page_size: u64,
+    pub writable: bool,
+    pub user: bool,
+    pub nx: bool,
+    pub pkey: u8,
+    pub global: bool,
+    pub accessed: bool,
+    pub dirty: bool,
 }
 
-impl fmt::Display for VirtMemError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+impl Level {
+    fn entry_addr(&self, table: PhyAddress, gva: Address) -> PhyAddress {
+        let index = (gva >> self.shift) & ((1 << self.index_bits) - 1);
+
+        table + index * self.entry_size as u64
+    }
+
+    fn next_table(&self, entry: u64) -> PhyAddress {
+        match self.entry_size {
+            4 => entry & 0xffff_f000,
+            _ => phy_mask(entry) & !0xfff,
+        }
+    }
+
+    fn page_base(&self, entry: u64) -> PhyAddress {
+        let size = 1 << self.shift;
+
+        match self.entry_size {
+            // 4M pages keep physical address bits 39:32 in entry bits 20:13
+            4 if size > 0x1000 => (entry & 0xffc0_0000) | ((entry >> 13) & 0xff) << 32,
+            4 => entry & 0xffff_f000,
+            // this also drops the PAT bit of large pages
+            _ => phy_mask(entry) & !(size - 1),
+        }
     }
 }
 
-impl Error for VirtMemError {
-    fn description(&self) -> &str {
-        "virtual to physical translation error"
+impl GuestMemory {
+    fn read_entry(&mut self, gpa: PhyAddress, sz: usize) -> Result<u64, PhyMemError> {
+        let mut buf = [0; mem::size_of::<u64>()];
+        self.phy_read_slice_checked(gpa, &mut buf[..sz])?;
+        Ok(u64::from_le_bytes(buf))
     }
 
-    fn cause(&self) -> Option<&dyn Error> {
-        None
+    /// Translate a linear address by walking the page tables rooted at `cr3`
+    /// in this memory
+    pub fn virt_translate_checked(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+    ) -> Result<PhyAddress, VirtMemError> {
+        self.virt_translation_checked(mode, cr3, gva).map(|t| t.gpa)
+    }
+
+    /// Walk the page tables like `virt_translate_checked`, also returning the
+    /// attributes of the mapping
+    pub fn virt_translation_checked(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+    ) -> Result<Translation, VirtMemError> {
+        mode.check_address(gva)?;
+
+        let cr3 = mode.table_base(cr3);
+
+        if let Some(t) = self.tlb.as_ref().and_then(|tlb| tlb.get(mode, cr3, gva)) {
+            return Ok(t);
+        }
+
+        let mut t = Translation {
+            gpa: gva,
+            page_size: 0x1000,
+            writable: true,
+            user: true,
+            nx: false,
+            pkey: 0,
+            global: false,
+            accessed: false,
+            dirty: false,
+        };
+
+        let levels = mode.levels();
+
+        if levels.is_empty() {
+            return Ok(t);
+        }
+
+        let mut table = cr3;
+
+        for (i, level) in levels.iter().enumerate() {
+            if let Some(tlb) = self.tlb.as_mut() {
+                tlb.add_table(table);
+            }
+
+            let entry = self.read_entry(level.entry_addr(table, gva), level.entry_size)?;
+
+            if entry & PTE_P == 0 {
+                return Err(level.not_present);
+            }
+
+            if level.rights {
+                t.writable &= entry & PTE_RW != 0;
+                t.user &= entry & PTE_US != 0;
+                t.nx |= level.entry_size == 8 && entry & PTE_XD != 0;
+            }
+
+            if i == levels.len() - 1 || (level.large && entry & PTE_PS != 0) {
+                let size = 1 << level.shift;
+
+                t.gpa = level.page_base(entry) + (gva & (size - 1));
+                t.page_size = size;
+                t.global = entry & PTE_G != 0;
+                t.accessed = entry & PTE_A != 0;
+                t.dirty = entry & PTE_D != 0;
+
+                if matches!(mode, PagingMode::Long4 | PagingMode::Long5) {
+                    t.pkey = ((entry >> 59) & 0xf) as u8;
+                }
+
+                if let Some(tlb) = self.tlb.as_mut() {
+                    tlb.insert(mode, cr3, gva, t);
+                }
+
+                return Ok(t);
+            }
+
+            table = level.next_table(entry);
+        }
+
+        unreachable!()
+    }
+
+    /// Check whether an access to `gva` would fault
+    ///
+    /// Returns the #PF error code the access would raise, or `None` if it
+    /// would succeed. Missing page table levels produce a not present fault,
+    /// other walk errors (e.g. non-canonical addresses, which raise #GP
+    /// rather than #PF) are returned as is.
+    pub fn virt_check_access(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+        access: MemAccess,
+        ctx: &AccessContext,
+    ) -> Result<Option<u32>, VirtMemError> {
+        match self.virt_translation_checked(mode, cr3, gva) {
+            Ok(t) => Ok(t.fault(mode, access, ctx)),
+            Err(
+                VirtMemError::Pml5eNotPresent
+                | VirtMemError::Pml4eNotPresent
+                | VirtMemError::PdpteNotPresent
+                | VirtMemError::PdeNotPresent
+                | VirtMemError::PteNotPresent,
+            ) => Ok(Some(ctx.error_code(mode, access) & !PF_P)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn virt_read_slice_checked(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+        buf: &mut [u8],
+    ) -> Result<(), VirtMemError> {
+        debug_assert!(gva.checked_add(buf.len() as u64).is_some());
+
+        let mut off = 0;
+
+        for (start, sz) in chunked(gva, buf.len()) {
+            let gpa = self.virt_translate_checked(mode, cr3, start)?;
+            self.phy_read_slice_checked(gpa, &mut buf[off..off + sz])?;
+            off += sz;
+        }
+
+        Ok(())
+    }
+
+    pub fn virt_write_checked(
+        &mut self,
+        mode: PagingMode,
+        cr3: PhyAddress,
+        gva: Address,
+        buf: &[u8],
+    ) -> Result<(), VirtMemError> {
+        debug_assert!(gva.checked_add(buf.len() as u64).is_some());
+
+        let mut off = 0;
+
+        for (start, sz) in chunked(gva, buf.len()) {
+            let gpa = self.virt_translate_checked(mode, cr3, start)?;
+
+            self.phy_write_checked(gpa, &buf[off..off + sz])?;
+
+            off += sz;
+        }
+
+        Ok(())
     }
 }
 
+/// A run of virtually contiguous pages mapping physically contiguous memory
+/// with the same page size and effective rights
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Mapping {
+    pub virt: RangeInclusive<Address>,
+    pub phys: PhyAddress,
+    pub page_size: u64,
+    pub writable: bool,
+    pub user: bool,
+    pub nx: bool,
+}
+
+impl Mapping {
+    pub fn size(&self) -> u64 {
+        self.virt.end() - self.virt.start() + 1
+    }
+
+    fn extends(&self, next: &Mapping) -> bool {
+        self.virt.end().wrapping_add(1) == *next.virt.start()
+            && self.phys + self.size() == next.phys
+            && self.page_size == next.page_size
+            && self.writable == next.writable
+            && self.user == next.user
+            && self.nx == next.nx
+    }
+}
+
+struct Frame {
+    level: usize,
+    entries: Vec<u64>,
+    next: usize,
+    base: Address,
+    writable: bool,
+    user: bool,
+    nx: bool,
+}
+
+/// Iterator over every mapping of a page table hierarchy, in ascending
+/// virtual address order
+///
+/// Page tables which are not present in guest memory are skipped.
+pub struct Mappings<'a> {
+    mem: &'a mut GuestMemory,
+    mode: PagingMode,
+    stack: Vec<Frame>,
+    run: Option<Mapping>,
+}
+
+impl Mappings<'_> {
+    fn push(&mut self, frame: Frame, table: PhyAddress) {
+        let level = &self.mode.levels()[frame.level];
+
+        let mut buf = vec![0; level.entry_size << level.index_bits];
+        if self.mem.phy_read_slice_checked(table, &mut buf).is_err() {
+            return;
+        }
+
+        let entries = buf
+            .chunks_exact(level.entry_size)
+            .map(|c| {
+                let mut e = [0; mem::size_of::<u64>()];
+                e[..c.len()].copy_from_slice(c);
+                u64::from_le_bytes(e)
+            })
+            .collect();
+
+        self.stack.push(Frame { entries, ..frame });
+    }
+
+    fn next_page(&mut self) -> Option<Mapping> {
+        let levels = self.mode.levels();
+
+        loop {
+            let frame = self.stack.last_mut()?;
+
+            if frame.next == frame.entries.len() {
+                self.stack.pop();
+                continue;
+            }
+
+            let idx = frame.next;
+            frame.next += 1;
+
+            let entry = frame.entries[idx];
+
+            if entry & PTE_P == 0 {
+                continue;
+            }
+
+            let level = &levels[frame.level];
+            let va = self
+                .mode
+                .canonical(frame.base + ((idx as Address) << level.shift));
+
+            let (mut writable, mut user, mut nx) = (frame.writable, frame.user, frame.nx);
+
+            if level.rights {
+                writable &= entry & PTE_RW != 0;
+                user &= entry & PTE_US != 0;
+                nx |= level.entry_size == 8 && entry & PTE_XD != 0;
+            }
+
+            if frame.level == levels.len() - 1 || (level.large && entry & PTE_PS != 0) {
+                let size = 1 << level.shift;
+
+                return Some(Mapping {
+                    virt: va..=va + (size - 1),
+                    phys: level.page_base(entry),
+                    page_size: size,
+                    writable,
+                    user,
+                    nx,
+                });
+            }
+
+            let next = Frame {
+                level: frame.level + 1,
+                entries: Vec::new(),
+                next: 0,
+                base: va,
+                writable,
+                user,
+                nx,
+            };
+
+            self.push(next, level.next_table(entry));
+        }
+    }
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let Some(page) = self.next_page() else {
+                return self.run.take();
+            };
+
+            match self.run.as_mut() {
+                Some(run) if run.extends(&page) => {
+                    run.virt = *run.virt.start()..=*page.virt.end();
+                }
+                _ => {
+                    if let Some(run) = self.run.replace(page) {
+                        return Some(run);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl GuestMemory {
+    /// Enumerate the address space described by the page tables at `cr3`
+    ///
+    /// Nothing is yielded with paging disabled, as there are no tables.
+    pub fn mappings(&mut self, mode: PagingMode, cr3: PhyAddress) -> Mappings<'_> {
+        let mut m = Mappings {
+            mem: self,
+            mode,
+            stack: Vec::new(),
+            run: None,
+        };
+
+        if !mode.levels().is_empty() {
+            let root = Frame {
+                level: 0,
+                entries: Vec::new(),
+                next: 0,
+                base: 0,
+                writable: true,
+                user: true,
+                nx: false,
+            };
+
+            m.push(root, mode.table_base(cr3));
+        }
+
+        m
+    }
+}
+
+// the functions without a mode assume 4-level long mode paging, the `_mode`
+// variants take the paging mode of the guest, e.g. from `Cpu::paging_mode`
+
+/// # Panics
+///
+/// Panics if any of the 8 bytes at `gva` is non-canonical or not mapped by the
+/// page tables at `cr3`, or if a page table in the walk is missing from guest
+/// memory or the data is. The same goes for the other sizes. Use
+/// `virt_read_slice_checked` to get an error instead.
 pub fn virt_read_u64(cr3: PhyAddress, gva: Address) -> u64 {
+    virt_read_u64_mode(PagingMode::Long4, cr3, gva)
+}
+
+pub fn virt_read_u64_mode(mode: PagingMode, cr3: PhyAddress, gva: Address) -> u64 {
     let mut buf = [0; mem::size_of::<u64>()];
-    virt_read_slice(cr3, gva, &mut buf);
+    virt_read_slice_mode(mode, cr3, gva, &mut buf);
     u64::from_le_bytes(buf)
 }
 
 pub fn virt_read_u32(cr3: PhyAddress, gva: Address) -> u32 {
+    virt_read_u32_mode(PagingMode::Long4, cr3, gva)
+}
+
+pub fn virt_read_u32_mode(mode: PagingMode, cr3: PhyAddress, gva: Address) -> u32 {
     let mut buf = [0; mem::size_of::<u32>()];
-    virt_read_slice(cr3, gva, &mut buf);
+    virt_read_slice_mode(mode, cr3, gva, &mut buf);
     u32::from_le_bytes(buf)
 }
 
 pub fn virt_read_u16(cr3: PhyAddress, gva: Address) -> u16 {
+    virt_read_u16_mode(PagingMode::Long4, cr3, gva)
+}
+
+pub fn virt_read_u16_mode(mode: PagingMode, cr3: PhyAddress, gva: Address) -> u16 {
     let mut buf = [0; mem::size_of::<u16>()];
-    virt_read_slice(cr3, gva, &mut buf);
+    virt_read_slice_mode(mode, cr3, gva, &mut buf);
     u16::from_le_bytes(buf)
 }
 
 pub fn virt_read_u8(cr3: PhyAddress, gva: Address) -> u8 {
+    virt_read_u8_mode(PagingMode::Long4, cr3, gva)
+}
+
+pub fn virt_read_u8_mode(mode: PagingMode, cr3: PhyAddress, gva: Address) -> u8 {
     let mut buf = [0; mem::size_of::<u8>()];
-    virt_read_slice(cr3, gva, &mut buf);
+    virt_read_slice_mode(mode, cr3, gva, &mut buf);
     u8::from_le_bytes(buf)
 }
 
-fn chunked(start: Address, sz: usize) -> impl Iterator<Item = (Address, usize)> {
-    debug_assert!(start.checked_add(sz as u64).is_some());
-
-    let mut remaining = sz;
-    let mut base = start;
-
-    iter::from_fn(move || {
-        if remaining == 0 {
-            None
-        } else {
-            let chunk_base = base;
-
-            let chunk_sz = if base as usize + remaining > (base as usize & !0xfff) + 0x1000 {
-                ((base & !0xfff) + 0x1000 - base) as usize
-            } else {
-                remaining
-            };
-
-            base += chunk_sz as Address;
-            remaining -= chunk_sz;
-
-            Some((chunk_base, chunk_sz))
-        }
-    })
+/// # Panics
+///
+/// Panics if any of the `sz` bytes at `gva` is non-canonical or not mapped by
+/// the page tables at `cr3`, or if a page table in the walk is missing from
+/// guest memory or the data is. Use `virt_read_checked` to get an error
+/// instead.
+pub fn virt_read(cr3: PhyAddress, gva: Address, buf: &mut Vec<u8>, sz: usize) {
+    virt_read_mode(PagingMode::Long4, cr3, gva, buf, sz)
 }
 
-pub fn virt_read(cr3: PhyAddress, gva: Address, buf: &mut Vec<u8>, sz: usize) {
-    virt_read_checked(cr3, gva, buf, sz).unwrap()
+pub fn virt_read_mode(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+    buf: &mut Vec<u8>,
+    sz: usize,
+) {
+    virt_read_mode_checked(mode, cr3, gva, buf, sz).unwrap()
 }
 
 pub fn virt_read_checked(
@@ -117,28 +744,43 @@ pub fn virt_read_checked(
     gva: Address,
     buf: &mut Vec<u8>,
     sz: usize,
+) -> Result<(), VirtMemError> {
+    virt_read_mode_checked(PagingMode::Long4, cr3, gva, buf, sz)
+}
+
+pub fn virt_read_mode_checked(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+    buf: &mut Vec<u8>,
+    sz: usize,
 ) -> Result<(), VirtMemError> {
     debug_assert!(gva.checked_add(sz as u64).is_some());
 
     let len = buf.len();
-    buf.reserve(sz);
-
-    unsafe {
-        buf.set_len(len + sz);
+    buf.resize(len + sz, 0);
 
-        let r = virt_read_slice_checked(cr3, gva, &mut buf[len..len + sz]);
+    let r = virt_read_slice_mode_checked(mode, cr3, gva, &mut buf[len..len + sz]);
 
-        // if we errored, roll the length back to the original
-        if r.is_err() {
-            buf.set_len(len);
-        }
-
-        r
+    // if we errored, roll the length back to the original
+    if r.is_err() {
+        buf.truncate(len);
     }
+
+    r
 }
 
+/// # Panics
+///
+/// Panics if any byte under `buf` is non-canonical or not mapped by the page
+/// tables at `cr3`, or if a page table in the walk is missing from guest memory
+/// or the data is. Use `virt_read_slice_checked` to get an error instead.
 pub fn virt_read_slice(cr3: PhyAddress, gva: Address, buf: &mut [u8]) {
-    virt_read_slice_checked(cr3, gva, buf).unwrap()
+    virt_read_slice_mode(PagingMode::Long4, cr3, gva, buf)
+}
+
+pub fn virt_read_slice_mode(mode: PagingMode, cr3: PhyAddress, gva: Address, buf: &mut [u8]) {
+    virt_read_slice_mode_checked(mode, cr3, gva, buf).unwrap()
 }
 
 pub fn virt_read_slice_checked(
@@ -146,95 +788,215 @@ pub fn virt_read_slice_checked(
     gva: Address,
     buf: &mut [u8],
 ) -> Result<(), VirtMemError> {
-    debug_assert!(gva.checked_add(buf.len() as u64).is_some());
-
-    let mut off = 0;
-
-    for (start, sz) in chunked(gva, buf.len()) {
-        let gpa = virt_translate_checked(cr3, start)?;
-        phy_read_slice(gpa, &mut buf[off..off + sz]);
-        off += sz;
-    }
+    virt_read_slice_mode_checked(PagingMode::Long4, cr3, gva, buf)
+}
 
-    Ok(())
+pub fn virt_read_slice_mode_checked(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+    buf: &mut [u8],
+) -> Result<(), VirtMemError> {
+    unsafe { default_memory().virt_read_slice_checked(mode, cr3, gva, buf) }
 }
 
+/// # Panics
+///
+/// Panics if any byte under `buf` is non-canonical or not mapped by the page
+/// tables at `cr3`, or if a page table in the walk is missing from guest memory
+/// or the data is, after the pages before it have been written. Use
+/// `virt_write_checked` to get an error instead.
 pub fn virt_write(cr3: PhyAddress, gva: Address, buf: &[u8]) {
-    virt_write_checked(cr3, gva, buf).unwrap()
+    virt_write_mode(PagingMode::Long4, cr3, gva, buf)
+}
+
+pub fn virt_write_mode(mode: PagingMode, cr3: PhyAddress, gva: Address, buf: &[u8]) {
+    virt_write_mode_checked(mode, cr3, gva, buf).unwrap()
 }
 
 pub fn virt_write_checked(cr3: PhyAddress, gva: Address, buf: &[u8]) -> Result<(), VirtMemError> {
-    debug_assert!(gva.checked_add(buf.len() as u64).is_some());
+    virt_write_mode_checked(PagingMode::Long4, cr3, gva, buf)
+}
 
-    let mut off = 0;
+pub fn virt_write_mode_checked(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+    buf: &[u8],
+) -> Result<(), VirtMemError> {
+    unsafe { default_memory().virt_write_checked(mode, cr3, gva, buf) }
+}
 
-    for (start, sz) in chunked(gva, buf.len()) {
-        let gpa = virt_translate_checked(cr3, start)?;
+/// # Panics
+///
+/// Panics if `gva` is non-canonical or not mapped by the page tables at `cr3`,
+/// or if a page table in the walk is missing from guest memory. Use
+/// `virt_translate_checked` to get an error instead.
+pub fn virt_translate(cr3: PhyAddress, gva: Address) -> PhyAddress {
+    virt_translate_mode(PagingMode::Long4, cr3, gva)
+}
 
-        phy_write(gpa, &buf[off..off + sz]);
+pub fn virt_translate_checked(cr3: PhyAddress, gva: Address) -> Result<PhyAddress, VirtMemError> {
+    virt_translate_mode_checked(PagingMode::Long4, cr3, gva)
+}
 
-        off += sz;
-    }
+pub fn virt_translate_mode(mode: PagingMode, cr3: PhyAddress, gva: Address) -> PhyAddress {
+    virt_translate_mode_checked(mode, cr3, gva).unwrap()
+}
 
-    Ok(())
+pub fn virt_translate_mode_checked(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+) -> Result<PhyAddress, VirtMemError> {
+    unsafe { default_memory().virt_translate_checked(mode, cr3, gva) }
 }
 
-pub fn virt_translate(cr3: PhyAddress, gva: Address) -> PhyAddress {
-    virt_translate_checked(cr3, gva).unwrap()
+pub fn virt_translation_checked(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+) -> Result<Translation, VirtMemError> {
+    unsafe { default_memory().virt_translation_checked(mode, cr3, gva) }
 }
 
-pub fn virt_translate_checked(cr3: PhyAddress, gva: Address) -> Result<PhyAddress, VirtMemError> {
-    let (pml4_base, _) = base_flags(cr3);
+pub fn virt_check_access(
+    mode: PagingMode,
+    cr3: PhyAddress,
+    gva: Address,
+    access: MemAccess,
+    ctx: &AccessContext,
+) -> Result<Option<u32>, VirtMemError> {
+    unsafe { default_memory().virt_check_access(mode, cr3, gva, access, ctx) }
+}
 
-    let pml4e_addr = pml4_base + pml4_index(gva) * 8;
-    let pml4e = phy_read_u64(pml4e_addr);
+/// # Safety
+///
+/// The iterator borrows the default `GuestMemory` for as long as it lives,
+/// so that memory must not be used elsewhere in the meantime.
+pub unsafe fn virt_mappings(mode: PagingMode, cr3: PhyAddress) -> Mappings<'static> {
+    unsafe { default_memory().mappings(mode, cr3) }
+}
 
-    let (pdpt_base, pml4e_flags) = base_flags(pml4e);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if pml4e_flags & 1 == 0 {
-        return Err(VirtMemError::Pml4eNotPresent);
-    }
+    #[repr(C, align(4096))]
+    struct Page([u8; 0x1000]);
 
-    let pdpte_addr = pdpt_base + pdpt_index(gva) * 8;
-    let pdpte = phy_read_u64(pdpte_addr);
+    const PML4: PhyAddress = 0x1000;
+    const PDPT: PhyAddress = 0x2000;
+    const PD: PhyAddress = 0x3000;
+    const PT: PhyAddress = 0x4000;
 
-    let (pd_base, pdpte_flags) = base_flags(pdpte);
+    // a 4-level hierarchy with its tables at 0x1000 through 0x4000
+    fn tables(pages: &mut [Page; 4]) -> GuestMemory {
+        let mut mem = GuestMemory::new();
 
-    if pdpte_flags & 1 == 0 {
-        return Err(VirtMemError::PdpteNotPresent);
-    }
+        for (i, p) in pages.iter_mut().enumerate() {
+            unsafe { mem.page_insert(PML4 + i as PhyAddress * 0x1000, p.0.as_mut_ptr()) };
+        }
+
+        let mut set = |table: PhyAddress, idx: u64, entry: u64| {
+            mem.phy_write_checked(table + idx * 8, &entry.to_le_bytes())
+                .unwrap();
+        };
+
+        set(PML4, 0, PDPT | PTE_P | PTE_RW | PTE_US);
+        set(PDPT, 0, PD | PTE_P | PTE_RW | PTE_US);
+        set(PD, 0, PT | PTE_P | PTE_RW | PTE_US);
 
-    // huge pages:
-    // 7 (PS) - Page size; must be 1 (otherwise, this entry references a page
-    // directory; see Table 4-1
-    if pdpte_flags & 1 << 7 != 0 {
-        return Ok((pd_base & 0xffff_ffff_c000_0000) + (gva & 0x3fff_ffff));
+        mem
     }
 
-    let pde_addr = pd_base + pd_index(gva) * 8;
-    let pde = phy_read_u64(pde_addr);
+    fn pte(mem: &mut GuestMemory, idx: u64, entry: u64) {
+        mem.phy_write_checked(PT + idx * 8, &entry.to_le_bytes())
+            .unwrap();
+    }
 
-    let (pt_base, pde_flags) = base_flags(pde);
+    fn run(virt: RangeInclusive<Address>, phys: PhyAddress, writable: bool) -> Mapping {
+        Mapping {
+            virt,
+            phys,
+            page_size: 0x1000,
+            writable,
+            user: true,
+            nx: false,
+        }
+    }
 
-    if pde_flags & 1 == 0 {
-        return Err(VirtMemError::PdeNotPresent);
+    #[test]
+    fn coalesce() {
+        let mut pages = [const { Page([0; 0x1000]) }; 4];
+        let mut mem = tables(&mut pages);
+
+        // three contiguous pages, a physical discontinuity, a read only page
+        // and a hole
+        pte(&mut mem, 0, 0x10000 | PTE_P | PTE_RW | PTE_US);
+        pte(&mut mem, 1, 0x11000 | PTE_P | PTE_RW | PTE_US);
+        pte(&mut mem, 2, 0x12000 | PTE_P | PTE_RW | PTE_US);
+        pte(&mut mem, 3, 0x20000 | PTE_P | PTE_RW | PTE_US);
+        pte(&mut mem, 4, 0x21000 | PTE_P | PTE_US);
+        pte(&mut mem, 6, 0x22000 | PTE_P | PTE_US);
+
+        let m: Vec<_> = mem.mappings(PagingMode::Long4, PML4).collect();
+
+        assert_eq!(
+            m,
+            [
+                run(0..=0x2fff, 0x10000, true),
+                run(0x3000..=0x3fff, 0x20000, true),
+                run(0x4000..=0x4fff, 0x21000, false),
+                run(0x6000..=0x6fff, 0x22000, false),
+            ]
+        );
     }
 
-    // large pages:
-    // 7 (PS) - Page size; must be 1 (otherwise, this entry references a page
-    // table; see Table 4-18
-    if pde_flags & 1 << 7 != 0 {
-        return Ok((pt_base & 0xffff_ffff_ffe0_0000) + (gva & 0x1f_ffff));
+    #[test]
+    fn page_sizes() {
+        let mut pages = [const { Page([0; 0x1000]) }; 4];
+        let mut mem = tables(&mut pages);
+
+        // the last 4K page runs into a physically contiguous 2M page, which
+        // still starts a new run
+        pte(&mut mem, 511, 0x1ff000 | PTE_P | PTE_RW | PTE_US);
+        mem.phy_write_checked(
+            PD + 8,
+            &(0x200000 | PTE_P | PTE_RW | PTE_US | PTE_PS).to_le_bytes(),
+        )
+        .unwrap();
+
+        let m: Vec<_> = mem.mappings(PagingMode::Long4, PML4).collect();
+
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[0], run(0x1ff000..=0x1fffff, 0x1ff000, true));
+        assert_eq!(m[1].virt, 0x200000..=0x3fffff);
+        assert_eq!(m[1].page_size, 0x200000);
     }
 
-    let pte_addr = pt_base + pt_index(gva) * 8;
-    let pte = phy_read_u64(pte_addr);
+    #[test]
+    fn canonical() {
+        let mut pages = [const { Page([0; 0x1000]) }; 4];
+        let mut mem = tables(&mut pages);
 
-    let (pte_paddr, pte_flags) = pte_flags(pte);
+        // the PML4 entry for the upper half reuses the same PDPT
+        mem.phy_write_checked(PML4 + 256 * 8, &(PDPT | PTE_P | PTE_RW).to_le_bytes())
+            .unwrap();
+        pte(&mut mem, 0, 0x10000 | PTE_P | PTE_RW | PTE_US);
 
-    if pte_flags & 1 == 0 {
-        return Err(VirtMemError::PteNotPresent);
+        let m: Vec<_> = mem.mappings(PagingMode::Long4, PML4).collect();
+
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[0], run(0..=0xfff, 0x10000, true));
+        assert_eq!(*m[1].virt.start(), 0xffff_8000_0000_0000);
+        assert!(!m[1].user);
     }
 
-    Ok(pte_paddr + page_offset(gva))
+    #[test]
+    fn paging_disabled() {
+        let mut mem = GuestMemory::new();
+
+        assert_eq!(mem.mappings(PagingMode::Disabled, 0).count(), 0);
+    }
 }
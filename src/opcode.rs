@@ -11,6 +11,9 @@ pub enum Opcode {
 
 unsafe extern "C-unwind" {
     pub fn instr_bx_opcode(_: *const c_void) -> u32;
+    pub fn instr_ilen(_: *const c_void) -> u32;
+    pub fn instr_is_rdmsr(_: *const c_void) -> bool;
+    pub fn instr_is_cpuid(_: *const c_void) -> bool;
     pub fn instr_imm16(_: *const c_void) -> u16;
     pub fn instr_imm32(_: *const c_void) -> u32;
     pub fn instr_imm64(_: *const c_void) -> u64;
@@ -15,14 +15,26 @@ unsafe extern "C-unwind" {
 
     fn sim_new_param_string(name: *const c_char, val: *const c_char, sz: u32) -> *mut c_void;
     fn sim_delete_param_string(n: *mut c_void);
+
+    fn sim_param_enum_get(e: *mut c_void) -> u32;
+    fn sim_param_enum_set(e: *mut c_void, idx: u32);
+    fn sim_param_num_get(n: *mut c_void) -> u64;
+    fn sim_param_num_set(n: *mut c_void, val: u64);
+    fn sim_param_bool_get(b: *mut c_void) -> bool;
+    fn sim_param_bool_set(b: *mut c_void, val: bool);
+    fn sim_param_string_get(s: *mut c_void) -> *const c_char;
+    fn sim_param_string_set(s: *mut c_void, val: *const c_char);
+
+    fn sim_param_parse(p: *mut c_void, val: *const c_char) -> i32;
 }
 
-#[expect(dead_code)]
+// the parsing is done by bochs' own parse_param, on a scratch parameter so
+// the real one is only changed once the caller decides to
+
 pub struct ParamEnum(pub *mut c_void, Vec<*const c_char>);
 impl ParamEnum {
     pub fn new(name: &'static CStr, val: &[&'static CStr], idx: usize) -> Self {
-        let mut a: Vec<*const c_char> =
-            val.iter().map(|x| x as *const _ as *const c_char).collect();
+        let mut a: Vec<*const c_char> = val.iter().map(|x| x.as_ptr()).collect();
         assert!(idx < a.len());
         a.push(ptr::null());
 
@@ -30,6 +42,37 @@ impl ParamEnum {
 
         Self(p, a)
     }
+
+    pub fn choices(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.1
+            .iter()
+            .take_while(|x| !x.is_null())
+            .map(|x| unsafe { CStr::from_ptr(*x) }.to_str().unwrap())
+    }
+
+    /// Parse `val` as a bochsrc value would be, returning the choice index
+    pub fn parse(&self, val: &CStr) -> Option<usize> {
+        unsafe {
+            let p = sim_new_param_enum(c"parse".as_ptr(), self.1.as_ptr(), 0);
+            let r = match sim_param_parse(p, val.as_ptr()) {
+                1 => Some(sim_param_enum_get(p) as usize),
+                _ => None,
+            };
+            sim_delete_param_enum(p);
+
+            r
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        unsafe { sim_param_enum_get(self.0) as usize }
+    }
+
+    pub fn set(&self, idx: usize) {
+        assert!(idx < self.1.len() - 1);
+
+        unsafe { sim_param_enum_set(self.0, idx as u32) }
+    }
 }
 impl Drop for ParamEnum {
     fn drop(&mut self) {
@@ -38,12 +81,49 @@ impl Drop for ParamEnum {
 }
 unsafe impl Sync for ParamEnum {}
 
-pub struct ParamNum(pub *mut c_void);
+pub struct ParamNum(pub *mut c_void, u64, u64);
 impl ParamNum {
     pub fn new(name: &'static CStr, min: u64, max: u64, val: u64) -> Self {
         let p = unsafe { sim_new_param_num(name.as_ptr(), min, max, val) };
 
-        Self(p)
+        Self(p, min, max)
+    }
+
+    pub fn range(&self) -> (u64, u64) {
+        (self.1, self.2)
+    }
+
+    /// Parse `val` as a bochsrc value would be, without checking it against
+    /// the range
+    pub fn parse(&self, val: &CStr) -> Option<u64> {
+        // bochs looks at the last character without checking the length
+        if val.is_empty() {
+            return None;
+        }
+
+        unsafe {
+            // bochs panics on values outside of the range, and skips the
+            // check when the maximum is u64::MAX
+            let p = sim_new_param_num(c"parse".as_ptr(), 0, u64::MAX, 0);
+            let r = match sim_param_parse(p, val.as_ptr()) {
+                1 => Some(sim_param_num_get(p)),
+                _ => None,
+            };
+            sim_delete_param_num(p);
+
+            r
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        unsafe { sim_param_num_get(self.0) }
+    }
+
+    // bochs panics on values outside of the range
+    pub fn set(&self, val: u64) {
+        assert!(val >= self.1 && val <= self.2);
+
+        unsafe { sim_param_num_set(self.0, val) }
     }
 }
 impl Drop for ParamNum {
@@ -60,6 +140,28 @@ impl ParamBool {
 
         Self(p)
     }
+
+    /// Parse `val` as a bochsrc value would be
+    pub fn parse(&self, val: &CStr) -> Option<bool> {
+        unsafe {
+            let p = sim_new_param_bool(c"parse".as_ptr(), 0);
+            let r = match sim_param_parse(p, val.as_ptr()) {
+                1 => Some(sim_param_bool_get(p)),
+                _ => None,
+            };
+            sim_delete_param_bool(p);
+
+            r
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        unsafe { sim_param_bool_get(self.0) }
+    }
+
+    pub fn set(&self, val: bool) {
+        unsafe { sim_param_bool_set(self.0, val) }
+    }
 }
 impl Drop for ParamBool {
     fn drop(&mut self) {
@@ -68,18 +170,32 @@ impl Drop for ParamBool {
 }
 unsafe impl Sync for ParamBool {}
 
-pub struct ParamString(pub *mut c_void);
+pub struct ParamString(pub *mut c_void, usize);
 impl ParamString {
-    pub fn new(name: &'static CStr, val: &'static CStr) -> Self {
-        let p = unsafe {
-            sim_new_param_string(
-                name.as_ptr(),
-                val.as_ptr(),
-                val.to_bytes_with_nul().len() as _,
-            )
-        };
+    /// `max_len` is the longest value the parameter can hold, not counting
+    /// the nul terminator
+    pub fn new(name: &'static CStr, val: &'static CStr, max_len: usize) -> Self {
+        assert!(val.to_bytes().len() <= max_len);
 
-        Self(p)
+        let p = unsafe { sim_new_param_string(name.as_ptr(), val.as_ptr(), (max_len + 1) as _) };
+
+        Self(p, max_len)
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.1
+    }
+
+    pub fn get(&self) -> String {
+        unsafe { CStr::from_ptr(sim_param_string_get(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn set(&self, val: &CStr) {
+        assert!(val.to_bytes().len() <= self.1);
+
+        unsafe { sim_param_string_set(self.0, val.as_ptr()) }
     }
 }
 impl Drop for ParamString {
@@ -1,12 +1,13 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{CStr, c_void};
 use std::os::raw::c_char;
 use std::ptr;
 use std::sync::OnceLock;
 
 use crate::NUM_CPUS;
+use crate::config::ParamKind;
 use crate::params::*;
-use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref};
+use crate::syncunsafecell::{SyncUnsafeCell, ptr_to_ref, ptr_to_ref_mut};
 
 static PARAMS_ENUM: OnceLock<SyncUnsafeCell<BTreeMap<&'static str, ParamEnum>>> = OnceLock::new();
 static PARAMS_NUM: OnceLock<SyncUnsafeCell<BTreeMap<&'static str, ParamNum>>> = OnceLock::new();
@@ -17,7 +18,7 @@ static PARAMS_STRING: OnceLock<SyncUnsafeCell<BTreeMap<&'static str, ParamString
 fn init_params_enum() -> SyncUnsafeCell<BTreeMap<&'static str, ParamEnum>> {
     let mut m = BTreeMap::new();
 
-    // from cpudb.h
+    // from cpudb.h, bochsrc selects the model by these names
     m.insert(
         "cpu.model",
         ParamEnum::new(
@@ -26,13 +27,13 @@ fn init_params_enum() -> SyncUnsafeCell<BTreeMap<&'static str, ParamEnum>> {
                 c"bx_generic",
                 c"i486dx4",
                 c"pentium",
-                c"pentium_mxx",
+                c"pentium_mmx",
                 c"amd_k6_2_chomper",
                 c"athlon_xp",
                 c"p2_klamath",
                 c"p3_katmai",
                 c"p4_willamette",
-                c"core_duo_t2500_yonah",
+                c"core_duo_t2400_yonah",
                 c"atom_n270",
                 c"p4_prescott_celeron_336",
                 c"athlon64_clawhammer",
@@ -94,9 +95,9 @@ fn init_params_num() -> SyncUnsafeCell<BTreeMap<&'static str, ParamNum>> {
     m.insert("cpuid.vmx", ParamNum::new(c"vmx", 0, 2, 2));
     m.insert("cpuid.bmi", ParamNum::new(c"bmi", 0, 2, 2));
 
-    // cannot find values for these vvv
-    m.insert("cpuid.stepping", ParamNum::new(c"stepping", 0, 0, 0));
-    m.insert("cpuid.model", ParamNum::new(c"model", 0, 0, 0));
+    // only used by bx_generic, the ranges are the width of the cpuid fields
+    m.insert("cpuid.stepping", ParamNum::new(c"stepping", 0, 0xf, 0));
+    m.insert("cpuid.model", ParamNum::new(c"model", 0, 0xff, 0));
     m.insert("cpuid.family", ParamNum::new(c"family", 0, 6, 6));
 
     SyncUnsafeCell::new(m)
@@ -137,44 +138,113 @@ fn init_params_bool() -> SyncUnsafeCell<BTreeMap<&'static str, ParamBool>> {
         "cpu.cpuid_limit_winnt",
         ParamBool::new(c"cpuid_limit_winnt", false),
     );
-    m.insert(
-        "cpu.ignore_bad_msrs",
-        ParamBool::new(c"ignore_bad_msrs", false),
-    );
     // this needs to be set to false, because the reset path calls DEV_cmos_get_reg(0x0f),
     // which segfaults as I haven't implemented that stub yet...
     m.insert(
         "cpu.reset_on_triple_fault",
         ParamBool::new(c"reset_on_triple_fault", false),
     );
+    m.insert("cpu.mwait_is_nop", ParamBool::new(c"mwait_is_nop", false));
     m.insert(
         "cpu.ignore_bad_msrs",
-        ParamBool::new(c"ignore_base_msrs", true),
+        ParamBool::new(c"ignore_bad_msrs", true),
     );
 
     SyncUnsafeCell::new(m)
 }
 
+// the brand string is the 48 bytes returned by cpuid leaves 0x80000002..4
+pub(crate) const BRAND_STRING_LEN: usize = 48;
+pub(crate) const FEATURES_LEN: usize = 512;
+pub(crate) const PATH_LEN: usize = 512;
+
 fn init_params_string() -> SyncUnsafeCell<BTreeMap<&'static str, ParamString>> {
     let mut m = BTreeMap::new();
 
-    // this key just needs to exist, doesnt need to be a valid file name
-    m.insert("cpu.msrs", ParamString::new(c"msrs", c""));
+    // a file of msr definitions loaded when the cpu is created, empty for none
+    m.insert("cpu.msrs", ParamString::new(c"msrs", c"", PATH_LEN));
+    // empty means the model's own brand string
     m.insert(
         "cpu.brand_string",
-        ParamString::new(c"Intel(R) Core(TM) i7-7800X CPU @ 3.50GHz", c""),
+        ParamString::new(c"brand_string", c"", BRAND_STRING_LEN),
     );
 
-    m.insert("cpu.add_features", ParamString::new(c"add_features", c""));
+    m.insert(
+        "cpu.add_features",
+        ParamString::new(c"add_features", c"", FEATURES_LEN),
+    );
 
     m.insert(
         "cpu.exclude_features",
-        ParamString::new(c"exclude_features", c""),
+        ParamString::new(c"exclude_features", c"", FEATURES_LEN),
     );
 
     SyncUnsafeCell::new(m)
 }
 
+// every key bochs asked for, and which of them it did not get
+static QUERIED: SyncUnsafeCell<BTreeSet<String>> = SyncUnsafeCell::new(BTreeSet::new());
+static MISSING: SyncUnsafeCell<BTreeSet<(ParamKind, String)>> =
+    SyncUnsafeCell::new(BTreeSet::new());
+
+// bochs looks some of these up on hot paths, e.g. on every unknown MSR
+// access, so only allocate the first time a key is seen
+fn record_lookup(kind: ParamKind, name: &str, found: bool) {
+    unsafe {
+        let queried = ptr_to_ref_mut(QUERIED.0.get());
+
+        if !queried.contains(name) {
+            queried.insert(name.to_string());
+        }
+
+        let missing = ptr_to_ref_mut(MISSING.0.get());
+
+        if !found && !missing.iter().any(|(k, n)| *k == kind && n == name) {
+            missing.insert((kind, name.to_string()));
+        }
+    }
+}
+
+pub(crate) fn param_queried(name: &str) -> bool {
+    unsafe { ptr_to_ref(QUERIED.0.get()) }.contains(name)
+}
+
+pub(crate) fn params_missing() -> &'static BTreeSet<(ParamKind, String)> {
+    unsafe { ptr_to_ref(MISSING.0.get()) }
+}
+
+pub(crate) fn params_enum() -> &'static BTreeMap<&'static str, ParamEnum> {
+    unsafe { ptr_to_ref(PARAMS_ENUM.get_or_init(init_params_enum).0.get()) }
+}
+
+pub(crate) fn params_num() -> &'static BTreeMap<&'static str, ParamNum> {
+    unsafe { ptr_to_ref(PARAMS_NUM.get_or_init(init_params_num).0.get()) }
+}
+
+pub(crate) fn params_bool() -> &'static BTreeMap<&'static str, ParamBool> {
+    unsafe { ptr_to_ref(PARAMS_BOOL.get_or_init(init_params_bool).0.get()) }
+}
+
+pub(crate) fn params_string() -> &'static BTreeMap<&'static str, ParamString> {
+    unsafe { ptr_to_ref(PARAMS_STRING.get_or_init(init_params_string).0.get()) }
+}
+
+pub(crate) fn param_enum(name: &str) -> Option<&'static ParamEnum> {
+    params_enum().get(name)
+}
+
+pub(crate) fn param_num(name: &str) -> Option<&'static ParamNum> {
+    params_num().get(name)
+}
+
+pub(crate) fn param_bool(name: &str) -> Option<&'static ParamBool> {
+    params_bool().get(name)
+}
+
+pub(crate) fn param_string(name: &str) -> Option<&'static ParamString> {
+    params_string().get(name)
+}
+
 #[unsafe(no_mangle)]
 extern "C-unwind" fn sim_get_param_enum(p: *const c_char) -> *mut c_void {
     let s = unsafe {
@@ -185,7 +255,10 @@ extern "C-unwind" fn sim_get_param_enum(p: *const c_char) -> *mut c_void {
 
     trace!("looking up enum param for {}...", s);
 
-    match unsafe { ptr_to_ref(PARAMS_ENUM.get_or_init(init_params_enum).0.get()) }.get(&s) {
+    let r = param_enum(s);
+    record_lookup(ParamKind::Enum, s, r.is_some());
+
+    match r {
         None => {
             warn!("no enum parameter: {}", s);
             ptr::null_mut::<c_void>()
@@ -204,7 +277,10 @@ extern "C-unwind" fn sim_get_param_num(p: *const c_char) -> *mut c_void {
 
     trace!("looking up num param for {}...", s);
 
-    match unsafe { ptr_to_ref(PARAMS_NUM.get_or_init(init_params_num).0.get()) }.get(&s) {
+    let r = param_num(s);
+    record_lookup(ParamKind::Num, s, r.is_some());
+
+    match r {
         None => {
             warn!("no num parameter: {}", s);
             ptr::null_mut::<c_void>()
@@ -223,7 +299,10 @@ extern "C-unwind" fn sim_get_param_bool(p: *const c_char) -> *mut c_void {
 
     trace!("looking up bool param for {}...", s);
 
-    match unsafe { ptr_to_ref(PARAMS_BOOL.get_or_init(init_params_bool).0.get()) }.get(&s) {
+    let r = param_bool(s);
+    record_lookup(ParamKind::Bool, s, r.is_some());
+
+    match r {
         None => {
             warn!("no bool parameter: {}", s);
             ptr::null_mut::<c_void>()
@@ -242,7 +321,10 @@ extern "C-unwind" fn sim_get_param_string(p: *const c_char) -> *mut c_void {
 
     trace!("looking up string param for {}...", s);
 
-    match unsafe { ptr_to_ref(PARAMS_STRING.get_or_init(init_params_string).0.get()) }.get(&s) {
+    let r = param_string(s);
+    record_lookup(ParamKind::String, s, r.is_some());
+
+    match r {
         None => {
             warn!("no string parameter: {}", s);
             ptr::null_mut::<c_void>()
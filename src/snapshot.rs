@@ -0,0 +1,118 @@
+use std::ptr;
+use std::slice;
+
+use crate::PhyAddress;
+use crate::cpu::{Cpu, State};
+use crate::mem::{FastMap64, GuestMemory, cpu_memory};
+
+struct Page {
+    hva: *mut u8,
+    data: Box<[u8]>,
+}
+
+/// A copy of a cpu's register state and of every page in its guest memory
+///
+/// The snapshot is taken from, and restored into, the `GuestMemory` bound to
+/// the cpu. If dirty tracking is enabled on that memory, restoring only
+/// touches the pages which were written, mapped, or unmapped since the
+/// snapshot was taken or last restored. Otherwise every page in the snapshot
+/// is copied back unconditionally.
+///
+/// Pages which were mapped after the snapshot was taken (for example by the
+/// missing page handler) are unmapped on restore. Their host memory is owned
+/// by whoever mapped them and is not freed. Likewise the host pages captured
+/// here must stay alive for as long as the snapshot may be restored.
+pub struct Snapshot {
+    state: State,
+    pages: FastMap64<PhyAddress, Page>,
+}
+
+impl Snapshot {
+    /// # Safety
+    ///
+    /// The cpu must not be running. The host pages of its memory must stay
+    /// allocated for as long as the snapshot may be restored.
+    pub unsafe fn new(cpu: &Cpu) -> Self {
+        unsafe {
+            let m = cpu_memory(cpu.id());
+
+            let pages = m
+                .pages()
+                .map(|(gpa, hva)| {
+                    let data = slice::from_raw_parts(hva, 0x1000).into();
+                    (gpa, Page { hva, data })
+                })
+                .collect();
+
+            // start tracking from here, and drop any writable host pointers
+            // bochs has cached so the next write to each page is seen again
+            m.clear_dirty();
+            cpu.flush_tlb();
+
+            Self {
+                state: cpu.state(),
+                pages,
+            }
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Guest physical addresses of every page captured in this snapshot
+    pub fn pages(&self) -> impl Iterator<Item = PhyAddress> + '_ {
+        self.pages.keys().copied()
+    }
+
+    /// # Safety
+    ///
+    /// The cpu must not be running, and the host pages captured by `new` must
+    /// still be allocated.
+    pub unsafe fn restore(&self, cpu: &Cpu) {
+        unsafe {
+            let m = cpu_memory(cpu.id());
+
+            if m.dirty_tracking() {
+                let dirty: Vec<PhyAddress> = m.dirty_pages().collect();
+
+                for gpa in dirty {
+                    self.restore_page(m, gpa);
+                }
+            } else {
+                let stale: Vec<PhyAddress> = m
+                    .pages()
+                    .map(|(gpa, _)| gpa)
+                    .filter(|gpa| !self.pages.contains_key(gpa))
+                    .collect();
+
+                for gpa in stale.into_iter().chain(self.pages.keys().copied()) {
+                    self.restore_page(m, gpa);
+                }
+            }
+
+            m.clear_dirty();
+            m.flush_translation_cache();
+
+            // memory has to be back in place before the caches are dropped,
+            // and set_state flushes the TLB, so the icache goes first
+            cpu.flush_icache();
+            cpu.set_state(&self.state);
+        }
+    }
+
+    unsafe fn restore_page(&self, m: &mut GuestMemory, gpa: PhyAddress) {
+        unsafe {
+            match self.pages.get(&gpa) {
+                None => m.page_remove(gpa),
+                Some(page) => {
+                    if m.page_hva(gpa) != Some(page.hva) {
+                        m.page_insert(gpa, page.hva);
+                    }
+
+                    ptr::copy_nonoverlapping(page.data.as_ptr(), page.hva, page.data.len());
+                }
+            }
+        }
+    }
+}